@@ -0,0 +1,290 @@
+use crate::{Chip8Error, CPU};
+use egui::{DragValue, Grid, TextEdit, Ui};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+/// Candidates listed in the memory search, which can otherwise number in the thousands
+const SEARCH_ROWS: usize = 50;
+
+/// Location held at a fixed value by a cheat
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheatTarget {
+    Address(u16),
+    Register(u8),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Cheat {
+    pub name: String,
+    #[serde(flatten)]
+    pub target: CheatTarget,
+    pub value: u8,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct CheatFile {
+    cheats: Vec<Cheat>,
+}
+
+/// Cheats for a ROM, saved to a file named after its hash
+#[derive(Default)]
+pub struct Cheats {
+    path: Option<PathBuf>,
+    list: Vec<Cheat>,
+    /// Result of the last save, shown in the debug menu
+    status: Option<String>,
+    /// Target of the cheat being added in the debug menu
+    new_target: Option<CheatTarget>,
+}
+
+impl Cheats {
+    /// Reads the cheats for the ROM with SHA-1 hash `hash` from `<directory>/<hash>.toml`, which
+    /// is also where they are saved. A missing file has no cheats.
+    pub fn load(directory: &Path, hash: &str) -> Result<Self, Chip8Error> {
+        let path = directory.join(format!("{hash}.toml"));
+        let list = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                toml::from_str::<CheatFile>(&contents)
+                    .map_err(Chip8Error::CheatsParse)?
+                    .cheats
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(Chip8Error::CheatsIo(error)),
+        };
+        Ok(Self {
+            path: Some(path),
+            list,
+            ..Self::default()
+        })
+    }
+
+    fn save(&self) -> Result<PathBuf, String> {
+        let path = self.path.clone().ok_or("no cheats file for this ROM")?;
+        let file = CheatFile {
+            cheats: self.list.clone(),
+        };
+        let contents = toml::to_string(&file).map_err(|error| error.to_string())?;
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory).map_err(|error| error.to_string())?;
+        }
+        fs::write(&path, contents).map_err(|error| error.to_string())?;
+        Ok(path)
+    }
+}
+
+/// Comparison used to narrow down the memory search
+#[derive(Clone, Copy)]
+enum Filter {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Filter {
+    fn matches(self, previous: u8, current: u8) -> bool {
+        match self {
+            Filter::Equal(value) => current == value,
+            Filter::Changed => current != previous,
+            Filter::Unchanged => current == previous,
+            Filter::Increased => current > previous,
+            Filter::Decreased => current < previous,
+        }
+    }
+}
+
+/// Finds memory holding a value of interest by repeatedly comparing against a snapshot
+#[derive(Default)]
+pub(crate) struct MemorySearch {
+    /// Memory when the search was started or last narrowed down
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+    value: u8,
+}
+
+impl CPU {
+    /// Holds memory and registers at the values set by cheats
+    pub fn set_cheats(&mut self, cheats: Cheats) {
+        self.cheats = cheats;
+    }
+
+    /// Writes the value of every enabled cheat, once per frame. The writes belong to the undo
+    /// record of the instruction that ended the frame, whose register snapshot already covers
+    /// the registers, so stepping back over it reverts them too.
+    pub(crate) fn apply_cheats(&mut self) {
+        for cheat in self.cheats.list.iter().filter(|cheat| cheat.enabled) {
+            match cheat.target {
+                CheatTarget::Address(address) => {
+                    let address = address as usize & 0xFFF;
+                    if let Some(history) = &mut self.history {
+                        history.memory(address, self.memory[address]);
+                    }
+                    self.memory[address] = cheat.value;
+                }
+                CheatTarget::Register(register) => {
+                    self.registers[register as usize & 0xF] = cheat.value;
+                }
+            }
+        }
+    }
+
+    fn memory_snapshot(&self) -> Vec<u8> {
+        (0..4096).map(|idx| self.memory[idx]).collect()
+    }
+
+    pub(crate) fn cheats_ui(&mut self, ui: &mut Ui) {
+        ui.collapsing("Cheats", |ui| {
+            let cheats = &mut self.cheats;
+            let mut removed = None;
+            Grid::new("cheats").striped(true).show(ui, |ui| {
+                for (idx, cheat) in cheats.list.iter_mut().enumerate() {
+                    ui.checkbox(&mut cheat.enabled, "");
+                    ui.add(TextEdit::singleline(&mut cheat.name).desired_width(120.0));
+                    ui.label(match cheat.target {
+                        CheatTarget::Address(address) => format!("0x{address:03x}"),
+                        CheatTarget::Register(register) => format!("V{register:X}"),
+                    });
+                    ui.add(DragValue::new(&mut cheat.value));
+                    if ui.small_button("Remove").clicked() {
+                        removed = Some(idx);
+                    }
+                    ui.end_row();
+                }
+            });
+            if let Some(idx) = removed {
+                cheats.list.remove(idx);
+            }
+            ui.horizontal(|ui| {
+                let target = cheats.new_target.get_or_insert(CheatTarget::Address(0x200));
+                let is_address = matches!(target, CheatTarget::Address(_));
+                if ui.radio(is_address, "Address").clicked() && !is_address {
+                    *target = CheatTarget::Address(0x200);
+                }
+                if ui.radio(!is_address, "Register").clicked() && is_address {
+                    *target = CheatTarget::Register(0);
+                }
+                match target {
+                    CheatTarget::Address(address) => {
+                        ui.add(DragValue::new(address).clamp_range(0..=4095));
+                    }
+                    CheatTarget::Register(register) => {
+                        ui.add(DragValue::new(register).clamp_range(0..=15).prefix("V"));
+                    }
+                }
+                if ui.button("Add").clicked() {
+                    let target = *target;
+                    let (name, value) = match target {
+                        CheatTarget::Address(address) => {
+                            (format!("0x{address:03x}"), self.memory[address as usize])
+                        }
+                        CheatTarget::Register(register) => {
+                            (format!("V{register:X}"), self.registers[register as usize])
+                        }
+                    };
+                    cheats.list.push(Cheat {
+                        name,
+                        target,
+                        value,
+                        enabled: true,
+                    });
+                }
+            });
+            ui.horizontal(|ui| {
+                if ui.button("Save Cheats").clicked() {
+                    cheats.status = Some(match cheats.save() {
+                        Ok(path) => format!("Saved to {}", path.display()),
+                        Err(error) => format!("Error saving cheats: {error}"),
+                    });
+                }
+                if let Some(status) = &cheats.status {
+                    ui.label(status);
+                }
+            });
+        });
+        ui.collapsing("Memory Search", |ui| {
+            let current = self.memory_snapshot();
+            let search = &mut self.search;
+            let mut filter = None;
+            ui.horizontal(|ui| {
+                if ui.button("New Search").clicked() {
+                    search.snapshot = current.clone();
+                    search.candidates = (0..4096).collect();
+                }
+                ui.label(format!("{} candidates", search.candidates.len()));
+            });
+            let searching = !search.snapshot.is_empty();
+            ui.add_enabled_ui(searching, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Equal to").clicked() {
+                        filter = Some(Filter::Equal(search.value));
+                    }
+                    ui.add(DragValue::new(&mut search.value));
+                });
+                ui.horizontal(|ui| {
+                    for (label, option) in [
+                        ("Changed", Filter::Changed),
+                        ("Unchanged", Filter::Unchanged),
+                        ("Increased", Filter::Increased),
+                        ("Decreased", Filter::Decreased),
+                    ] {
+                        if ui.button(label).clicked() {
+                            filter = Some(option);
+                        }
+                    }
+                });
+            });
+            if let Some(filter) = filter {
+                let previous = &search.snapshot;
+                search.candidates.retain(|&address| {
+                    let address = address as usize;
+                    filter.matches(previous[address], current[address])
+                });
+                search.snapshot = current.clone();
+            }
+            let mut frozen = None;
+            Grid::new("memory_search").striped(true).show(ui, |ui| {
+                ui.label("Address");
+                ui.label("Previous");
+                ui.label("Current");
+                ui.end_row();
+                for &address in search.candidates.iter().take(SEARCH_ROWS) {
+                    let idx = address as usize;
+                    ui.label(format!("0x{address:03x}"));
+                    ui.label(search.snapshot[idx].to_string());
+                    ui.label(current[idx].to_string());
+                    if ui.small_button("Freeze").clicked() {
+                        frozen = Some((address, current[idx]));
+                    }
+                    ui.end_row();
+                }
+            });
+            if search.candidates.len() > SEARCH_ROWS {
+                ui.label(format!(
+                    "{} more not shown",
+                    search.candidates.len() - SEARCH_ROWS
+                ));
+            }
+            if let Some((address, value)) = frozen {
+                self.cheats.list.push(Cheat {
+                    name: format!("0x{address:03x}"),
+                    target: CheatTarget::Address(address),
+                    value,
+                    enabled: true,
+                });
+            }
+        });
+    }
+}
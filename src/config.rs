@@ -0,0 +1,196 @@
+use crate::{
+    database::{self, RomInfo},
+    input::{HostKey, KeypadKey},
+    quirks::{Platform, Quirks},
+    settings::{HexColour, Multiplier, Persistence, Scaling, Screenshots, Theme},
+    Chip8Error,
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+/// Contents of the TOML configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub palette: Option<Theme>,
+    /// Up to four colours replacing those of `palette`, in bitplane order
+    pub colours: Vec<HexColour>,
+    pub foreground: Option<HexColour>,
+    pub background: Option<HexColour>,
+    pub persistence: Persistence,
+    pub scaling: Scaling,
+    pub fullscreen: bool,
+    pub screenshots: Screenshots,
+    /// Host keys for each keypad key, replacing the default ones
+    pub keys: BTreeMap<KeypadKey, Vec<HostKey>>,
+    /// Show the on-screen keypad
+    pub keypad: bool,
+    /// Text shown on each key of the on-screen keypad
+    pub labels: BTreeMap<KeypadKey, String>,
+    pub quirks: Quirks,
+    /// Instructions executed per frame [default: 1]
+    pub speed: Option<usize>,
+    /// Emulation rate relative to normal, from 0.25 to 16 or "unthrottled" [default: 1]
+    pub multiplier: Option<Multiplier>,
+    /// Rate while the fast-forward key is held [default: 8]
+    pub fast_forward: Option<Multiplier>,
+    /// Beeper volume between 0 and 1 [default: 1]
+    pub volume: Option<f32>,
+    pub mute: bool,
+    /// Directory of patches named after the SHA-1 hash of the ROM they apply to
+    /// [default: chippy/patches in the user config directory]
+    pub patches: Option<PathBuf>,
+    /// Directory where cheats are saved, named after the SHA-1 hash of the ROM they apply to
+    /// [default: chippy/cheats in the user config directory]
+    pub cheats: Option<PathBuf>,
+    /// Overrides for ROMs, keyed by file name without the extension or by SHA-1 hash
+    pub roms: BTreeMap<String, RomConfig>,
+}
+
+/// Options specific to a ROM, from the bundled database, the config file or an Octo cartridge
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RomConfig {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub platform: Option<Platform>,
+    pub quirks: Option<Quirks>,
+    pub speed: Option<usize>,
+    pub colours: Vec<HexColour>,
+    pub keys: BTreeMap<KeypadKey, Vec<HostKey>>,
+    pub labels: BTreeMap<KeypadKey, String>,
+}
+
+impl Config {
+    /// Reads `path`, or `chippy/config.toml` in the user's configuration directory if it is
+    /// `None`, in which case a missing file is not an error
+    pub fn load(path: Option<&Path>) -> Result<Self, Chip8Error> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match dirs::config_dir() {
+                Some(directory) => (directory.join("chippy").join("config.toml"), false),
+                None => return Ok(Self::default()),
+            },
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if !required && error.kind() == ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(error) => return Err(Chip8Error::ConfigIo(error)),
+        };
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Directory searched for patches to the loaded ROM
+    pub fn patch_directory(&self) -> Option<PathBuf> {
+        self.patches
+            .clone()
+            .or_else(|| Some(dirs::config_dir()?.join("chippy").join("patches")))
+    }
+
+    /// Directory where cheats for each ROM are loaded from and saved to
+    pub fn cheat_directory(&self) -> Option<PathBuf> {
+        self.cheats
+            .clone()
+            .or_else(|| Some(dirs::config_dir()?.join("chippy").join("cheats")))
+    }
+
+    /// Applies the options embedded in the ROM file, the database entry for the ROM, and the
+    /// overrides for it in the config file by name and then by hash, in that order
+    pub fn select_rom(
+        &mut self,
+        name: &str,
+        loaded: &RomInfo,
+        embedded: Option<RomConfig>,
+    ) -> Result<RomInfo, Chip8Error> {
+        let mut info = loaded.clone();
+        let database = database::lookup_rom(&info.hash)?;
+        let overrides = [self.roms.remove(name), self.roms.remove(&info.hash)];
+        let mut quirks = None;
+        for rom in [embedded, database].into_iter().chain(overrides).flatten() {
+            info.title = rom.title.or(info.title);
+            info.author = rom.author.or(info.author);
+            info.platform = rom.platform.or(info.platform);
+            quirks = rom.quirks.or(quirks);
+            self.speed = rom.speed.or(self.speed);
+            if !rom.colours.is_empty() {
+                self.colours = rom.colours;
+            }
+            self.keys.extend(rom.keys);
+            self.labels.extend(rom.labels);
+        }
+        // Quirks given for the ROM replace those of the config file, which its platform adjusts
+        let base = match info.platform {
+            Some(platform) => platform.quirks(self.quirks),
+            None => self.quirks,
+        };
+        self.quirks = quirks.unwrap_or(base);
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::HostKey;
+    use macroquad::input::KeyCode;
+
+    #[test]
+    fn keys() {
+        let config: Config = toml::from_str(
+            r#"
+            keys = { 5 = ["W", "Up"], a = [] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.keys[&KeypadKey(5)],
+            [HostKey(KeyCode::W), HostKey(KeyCode::Up)]
+        );
+        assert!(config.keys[&KeypadKey(0xA)].is_empty());
+    }
+
+    #[test]
+    fn platform_quirks() {
+        let rom = |platform| RomInfo {
+            platform: Some(platform),
+            ..RomInfo::default()
+        };
+        // CHIP-8 keeps the quirks of the config file
+        let mut config: Config = toml::from_str("quirks.flag_last = true").unwrap();
+        config
+            .select_rom("test", &rom(Platform::Chip8), None)
+            .unwrap();
+        assert!(config.quirks.flag_last && !config.quirks.shift_vy && !config.quirks.clip);
+
+        // Other platforms adjust them
+        let mut config: Config = toml::from_str("quirks.flag_last = true").unwrap();
+        config
+            .select_rom("test", &rom(Platform::Superchip), None)
+            .unwrap();
+        assert!(config.quirks.flag_last && config.quirks.jump_vx && config.quirks.clip);
+
+        // Quirks given for the ROM replace both
+        let mut config: Config = toml::from_str(
+            "quirks.flag_last = true
+            roms.test.quirks.shift_vy = true",
+        )
+        .unwrap();
+        config
+            .select_rom("test", &rom(Platform::Superchip), None)
+            .unwrap();
+        assert!(config.quirks.shift_vy && !config.quirks.flag_last && !config.quirks.jump_vx);
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        assert!(toml::from_str::<Config>(r#"keys = { 5 = ["Nope"] }"#).is_err());
+        assert!(toml::from_str::<Config>(r#"keys = { 16 = ["W"] }"#).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::{disassemble, Chip8Error, CPU};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+/// Records which addresses were executed as instructions and which were accessed as data
+pub struct Coverage {
+    path: PathBuf,
+    executed: Vec<bool>,
+    data: Vec<bool>,
+}
+
+impl Coverage {
+    /// The report is written to `path` when emulation ends, as HTML if it ends in `.html`
+    /// and as plain text otherwise
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            executed: vec![false; 4096],
+            data: vec![false; 4096],
+        }
+    }
+
+    pub(crate) fn executed(&mut self, address: usize) {
+        self.executed[address] = true;
+    }
+
+    pub(crate) fn data(&mut self, address: usize) {
+        if let Some(data) = self.data.get_mut(address) {
+            *data = true;
+        }
+    }
+
+    /// Coverage of the two bytes starting at `address`, as shown in the disassembly
+    fn row(&self, address: usize) -> (bool, bool) {
+        let executed = self.executed[address] || self.executed[address + 1];
+        let data = self.data[address] || self.data[address + 1];
+        (executed, data)
+    }
+
+    pub(crate) fn write_report(&self, cpu: &CPU) -> Result<(), Chip8Error> {
+        let html = self
+            .path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("html"));
+        let file = File::create(&self.path).map_err(Chip8Error::Coverage)?;
+        let mut writer = BufWriter::new(file);
+        let result = if html {
+            self.write_html(cpu, &mut writer)
+        } else {
+            self.write_text(cpu, &mut writer)
+        };
+        result
+            .and_then(|_| writer.flush())
+            .map_err(Chip8Error::Coverage)
+    }
+
+    fn rows(&self, cpu: &CPU) -> impl Iterator<Item = usize> {
+        // Rounded up to whole instructions
+        let end = 0x200 + cpu.rom_size.div_ceil(2) * 2;
+        (0x200..end.min(4096)).step_by(2)
+    }
+
+    fn summary(&self, cpu: &CPU) -> String {
+        let rows = self.rows(cpu).count();
+        let executed = self
+            .rows(cpu)
+            .filter(|&address| self.row(address).0)
+            .count();
+        let data = (0x200..0x200 + cpu.rom_size)
+            .filter(|&address| self.data[address])
+            .count();
+        let percentage = if rows == 0 {
+            0.0
+        } else {
+            executed as f64 * 100.0 / rows as f64
+        };
+        format!(
+            "{executed}/{rows} instructions executed ({percentage:.1}%), {data}/{} bytes accessed as data",
+            cpu.rom_size
+        )
+    }
+
+    fn write_text(&self, cpu: &CPU, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "{}", self.summary(cpu))?;
+        writeln!(writer, "X = executed, D = accessed as data")?;
+        writeln!(writer)?;
+        for address in self.rows(cpu) {
+            let (executed, data) = self.row(address);
+            let opcode = cpu.fetch(address);
+            writeln!(
+                writer,
+                "{}{} 0x{address:04x} {opcode:04x} {}",
+                if executed { 'X' } else { ' ' },
+                if data { 'D' } else { ' ' },
+                disassemble(opcode)
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_html(&self, cpu: &CPU, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "<!DOCTYPE html>")?;
+        writeln!(
+            writer,
+            "<html><head><meta charset=\"utf-8\"><title>Coverage</title>"
+        )?;
+        writeln!(
+            writer,
+            "<style>body {{ font-family: monospace; background: #111; color: #ccc; }} \
+             td {{ padding: 0 1em; }} .x {{ background: #1d4d1d; }} .d {{ background: #1d2d5d; }} \
+             .xd {{ background: #4d4d1d; }}</style></head><body>"
+        )?;
+        writeln!(writer, "<p>{}</p>", self.summary(cpu))?;
+        writeln!(
+            writer,
+            "<p><span class=\"x\">executed</span> <span class=\"d\">accessed as data</span> \
+             <span class=\"xd\">both</span></p>"
+        )?;
+        writeln!(writer, "<table>")?;
+        for address in self.rows(cpu) {
+            let class = match self.row(address) {
+                (true, true) => " class=\"xd\"",
+                (true, false) => " class=\"x\"",
+                (false, true) => " class=\"d\"",
+                (false, false) => "",
+            };
+            let opcode = cpu.fetch(address);
+            writeln!(
+                writer,
+                "<tr{class}><td>0x{address:04x}</td><td>{opcode:04x}</td><td>{}</td></tr>",
+                disassemble(opcode)
+            )?;
+        }
+        writeln!(writer, "</table></body></html>")
+    }
+}
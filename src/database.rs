@@ -0,0 +1,21 @@
+use crate::{config::RomConfig, quirks::Platform, Chip8Error};
+use std::collections::BTreeMap;
+
+/// Settings for known ROMs keyed by the SHA-1 hash of their contents
+const DATABASE: &str = include_str!("../assets/roms.toml");
+
+/// Looks up the bundled settings for the ROM with the hex SHA-1 hash `hash`
+pub fn lookup_rom(hash: &str) -> Result<Option<RomConfig>, Chip8Error> {
+    let mut roms: BTreeMap<String, RomConfig> = toml::from_str(DATABASE)?;
+    Ok(roms.remove(hash))
+}
+
+/// Description of the loaded ROM
+#[derive(Clone, Debug, Default)]
+pub struct RomInfo {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub platform: Option<Platform>,
+    /// Hex SHA-1 hash of the ROM, as used to key the database
+    pub hash: String,
+}
@@ -0,0 +1,393 @@
+use crate::{disassemble, CPU, STACK_SIZE};
+use egui::{vec2, Align, Color32, DragValue, Label, RichText, ScrollArea, Sense, TextStyle};
+use macroquad::prelude::*;
+use std::collections::BTreeSet;
+
+/// Condition that puts a stepping debugger back into the halted state
+#[derive(Clone, Copy)]
+enum StepMode {
+    /// Execute a single instruction
+    Into,
+    /// Run until the stack unwinds to (or below) the given depth
+    Over(u8),
+    /// Run until the stack unwinds below the given depth
+    Out(u8),
+    /// Run until the program counter reaches the given address
+    Cursor(u16),
+    /// Run until the timers tick
+    Frame,
+}
+
+pub(crate) struct Debugger {
+    halted: bool,
+    error: bool,
+    step: Option<StepMode>,
+    cursor: Option<u16>,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl Debugger {
+    pub(crate) fn new(halted: bool) -> Self {
+        Self {
+            halted,
+            error: false,
+            step: None,
+            cursor: None,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Whether the CPU should execute instructions this frame
+    pub(crate) fn is_running(&self) -> bool {
+        !self.error && (!self.halted || self.step.is_some())
+    }
+
+    /// Whether a step that may span many instructions is in progress
+    pub(crate) fn is_stepping(&self) -> bool {
+        !matches!(self.step, None | Some(StepMode::Into))
+    }
+
+    pub(crate) fn is_faulted(&self) -> bool {
+        self.error
+    }
+
+    pub(crate) fn fault(&mut self) {
+        self.halted = true;
+        self.error = true;
+        self.step = None;
+    }
+
+    pub(crate) fn halt(&mut self) {
+        self.halted = true;
+        self.step = None;
+    }
+
+    /// Returns to the initial state after the CPU is reset, keeping breakpoints
+    pub(crate) fn reset(&mut self, halted: bool) {
+        self.halted = halted;
+        self.error = false;
+        self.step = None;
+    }
+
+    pub(crate) fn resume(&mut self) {
+        self.halted = false;
+        self.step = None;
+    }
+
+    fn toggle(&mut self) {
+        self.halted = !self.halted;
+        self.step = None;
+    }
+
+    fn start_step(&mut self, mode: StepMode) {
+        if self.halted && !self.error && self.step.is_none() {
+            self.step = Some(mode);
+        }
+    }
+
+    pub(crate) fn step_into(&mut self) {
+        self.start_step(StepMode::Into);
+    }
+
+    fn step_over(&mut self, cpu: &CPU) {
+        let opcode = cpu.fetch(cpu.program_counter as usize);
+        if opcode & 0xF000 == 0x2000 {
+            self.start_step(StepMode::Over(cpu.stack_pointer));
+        } else {
+            self.start_step(StepMode::Into);
+        }
+    }
+
+    fn step_out(&mut self, cpu: &CPU) {
+        if cpu.stack_pointer > 0 {
+            self.start_step(StepMode::Out(cpu.stack_pointer));
+        }
+    }
+
+    fn run_to_cursor(&mut self) {
+        if let Some(cursor) = self.cursor {
+            self.start_step(StepMode::Cursor(cursor));
+        }
+    }
+
+    fn step_frame(&mut self) {
+        self.start_step(StepMode::Frame);
+    }
+
+    pub(crate) fn insert_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub(crate) fn remove_breakpoint(&mut self, address: u16) {
+        self.breakpoints.remove(&address);
+    }
+
+    fn toggle_breakpoint(&mut self, address: u16) {
+        if !self.breakpoints.remove(&address) {
+            self.breakpoints.insert(address);
+        }
+    }
+
+    /// Undoes the last instruction, which also recovers from a fatal error
+    fn step_back(&mut self, cpu: &mut CPU) {
+        if self.halted && self.step.is_none() && cpu.undo() {
+            self.error = false;
+        }
+    }
+
+    /// Undoes instructions until a breakpoint is reached or the history runs out
+    fn reverse_continue(&mut self, cpu: &mut CPU) {
+        if !self.halted || self.step.is_some() {
+            return;
+        }
+        while cpu.undo() {
+            self.error = false;
+            if self.breakpoints.contains(&cpu.program_counter) {
+                break;
+            }
+        }
+    }
+
+    /// Ends the current step once its stop condition is met, or halts on a breakpoint
+    pub(crate) fn after_step(&mut self, cpu: &CPU) {
+        if self.breakpoints.contains(&cpu.program_counter) {
+            self.halted = true;
+            self.step = None;
+            return;
+        }
+        let done = match self.step {
+            None => return,
+            Some(StepMode::Into) => true,
+            Some(StepMode::Over(depth)) => cpu.stack_pointer <= depth,
+            Some(StepMode::Out(depth)) => cpu.stack_pointer < depth,
+            Some(StepMode::Cursor(address)) => cpu.program_counter == address,
+            Some(StepMode::Frame) => cpu.timer == 0,
+        };
+        if done {
+            self.step = None;
+        }
+    }
+
+    /// F5: start/stop, F6: step frame, F7: step into, F8: step over,
+    /// Shift+F8: step out, F4: run to cursor, F9: toggle breakpoint at cursor,
+    /// Shift+F7: step back, Shift+F5: reverse continue
+    pub(crate) fn handle_shortcuts(&mut self, cpu: &mut CPU) {
+        let shift = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+        if is_key_pressed(KeyCode::F9) {
+            if let Some(cursor) = self.cursor {
+                self.toggle_breakpoint(cursor);
+            }
+        }
+        if shift {
+            if is_key_pressed(KeyCode::F5) {
+                self.reverse_continue(cpu);
+            }
+            if is_key_pressed(KeyCode::F7) {
+                self.step_back(cpu);
+            }
+            if is_key_pressed(KeyCode::F8) {
+                self.step_out(cpu);
+            }
+            return;
+        }
+        if is_key_pressed(KeyCode::F5) && !self.error {
+            self.toggle();
+        }
+        if is_key_pressed(KeyCode::F6) {
+            self.step_frame();
+        }
+        if is_key_pressed(KeyCode::F7) {
+            self.step_into();
+        }
+        if is_key_pressed(KeyCode::F8) {
+            self.step_over(cpu);
+        }
+        if is_key_pressed(KeyCode::F4) {
+            self.run_to_cursor();
+        }
+    }
+}
+
+impl CPU {
+    pub(crate) fn debug_menu(
+        &mut self,
+        egui_ctx: &egui::Context,
+        debugger: &mut Debugger,
+        debug: u8,
+    ) {
+        egui::Window::new("Debug Menu").show(egui_ctx, |ui| {
+            ui.label(format!("FPS: {}", get_fps()));
+            if debug > 1 {
+                let editable = !debugger.is_running();
+                ui.separator();
+                let scroll_area = ScrollArea::vertical()
+                    .max_height(200.0)
+                    .auto_shrink([false; 2]);
+                scroll_area.show(ui, |ui| {
+                    ui.vertical(|ui| {
+                        let mut address = 0x200;
+                        while address < 4096 {
+                            let opcode = self.fetch(address);
+                            let text = format!("0x{address:04x} - {}", disassemble(opcode));
+                            let text = if self.program_counter as usize == address {
+                                RichText::new(text).color(Color32::YELLOW)
+                            } else if debugger.cursor == Some(address as u16) {
+                                RichText::new(text).color(Color32::LIGHT_BLUE)
+                            } else {
+                                RichText::new(text)
+                            };
+                            let response = ui
+                                .horizontal(|ui| {
+                                    if let Some(profiler) = &self.profiler {
+                                        let (rect, _) = ui.allocate_exact_size(
+                                            vec2(6.0, ui.text_style_height(&TextStyle::Body)),
+                                            Sense::hover(),
+                                        );
+                                        ui.painter().rect_filled(rect, 0.0, profiler.heat(address));
+                                    }
+                                    // Clicking the gutter toggles a breakpoint
+                                    let address = address as u16;
+                                    let marker = if debugger.breakpoints.contains(&address) {
+                                        RichText::new("●").color(Color32::RED)
+                                    } else {
+                                        RichText::new("○").color(Color32::DARK_GRAY)
+                                    };
+                                    if ui.add(Label::new(marker).sense(Sense::click())).clicked() {
+                                        debugger.toggle_breakpoint(address);
+                                    }
+                                    ui.add(Label::new(text).sense(Sense::click()))
+                                })
+                                .inner;
+                            if response.secondary_clicked() {
+                                // Right click places the cursor used by "Run to Cursor"
+                                debugger.cursor = Some(address as u16);
+                            }
+                            if self.program_counter as usize == address {
+                                response.scroll_to_me(Some(Align::Min));
+                            } else if editable && response.clicked() {
+                                // Jump execution to the clicked instruction
+                                self.program_counter = address as u16;
+                            }
+                            address += 2;
+                        }
+                    });
+                });
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.vertical(|ui| {
+                        for idx in 0..16 {
+                            if editable {
+                                ui.add(
+                                    DragValue::new(&mut self.registers[idx])
+                                        .prefix(format!("V{idx}: ")),
+                                );
+                            } else {
+                                let register = self.registers[idx];
+                                ui.label(format!("V{idx}: {register}"));
+                            }
+                        }
+                    });
+                    ui.separator();
+                    ui.vertical(|ui| {
+                        if editable {
+                            // Values are clamped so that fetching and stack accesses stay in bounds
+                            ui.add(
+                                DragValue::new(&mut self.program_counter)
+                                    .clamp_range(0..=4094)
+                                    .prefix("PC: "),
+                            );
+                            ui.add(
+                                DragValue::new(&mut self.index_register)
+                                    .clamp_range(0..=4095)
+                                    .prefix("I: "),
+                            );
+                            ui.add(
+                                DragValue::new(&mut self.stack_pointer)
+                                    .clamp_range(0..=STACK_SIZE)
+                                    .prefix("SP: "),
+                            );
+                            ui.add(DragValue::new(&mut self.delay_timer).prefix("DT: "));
+                            ui.add(DragValue::new(&mut self.sound_timer).prefix("ST: "));
+                        } else {
+                            ui.label(format!("PC: {}", self.program_counter));
+                            ui.label(format!("I: {}", self.index_register));
+                            ui.label(format!("SP: {}", self.stack_pointer));
+                            ui.label(format!("DT: {}", self.delay_timer));
+                            ui.label(format!("ST: {}", self.sound_timer));
+                        }
+                    });
+                    ui.separator();
+                    ui.vertical(|ui| {
+                        if debugger.halted {
+                            let history = self.history.as_ref().map_or(0, |history| history.len());
+                            if ui
+                                .add_enabled(history > 0, egui::Button::new("Step Back (Shift+F7)"))
+                                .clicked()
+                            {
+                                debugger.step_back(self);
+                            }
+                            if ui
+                                .add_enabled(
+                                    history > 0,
+                                    egui::Button::new("Reverse Continue (Shift+F5)"),
+                                )
+                                .clicked()
+                            {
+                                debugger.reverse_continue(self);
+                            }
+                            ui.label(format!("History: {history}"));
+                        }
+                        if !debugger.error {
+                            let text = if debugger.halted {
+                                "Start (F5)"
+                            } else {
+                                "Stop (F5)"
+                            };
+                            if ui.button(text).clicked() {
+                                debugger.toggle();
+                            }
+                            if debugger.halted {
+                                if ui.button("Step Into (F7)").clicked() {
+                                    debugger.step_into();
+                                }
+                                if ui.button("Step Over (F8)").clicked() {
+                                    debugger.step_over(self);
+                                }
+                                let can_step_out = self.stack_pointer > 0;
+                                if ui
+                                    .add_enabled(
+                                        can_step_out,
+                                        egui::Button::new("Step Out (Shift+F8)"),
+                                    )
+                                    .clicked()
+                                {
+                                    debugger.step_out(self);
+                                }
+                                if ui
+                                    .add_enabled(
+                                        debugger.cursor.is_some(),
+                                        egui::Button::new("Run to Cursor (F4)"),
+                                    )
+                                    .clicked()
+                                {
+                                    debugger.run_to_cursor();
+                                }
+                                if ui.button("Step Frame (F6)").clicked() {
+                                    debugger.step_frame();
+                                }
+                            }
+                        } else {
+                            ui.label("A fatal error occurred!");
+                        }
+                    })
+                });
+            }
+            if self.profiler.is_some() {
+                ui.separator();
+                self.profiler_ui(ui);
+            }
+            ui.separator();
+            self.cheats_ui(ui);
+        });
+    }
+}
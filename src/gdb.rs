@@ -0,0 +1,591 @@
+use crate::{debugger::Debugger, Chip8Error, CPU, STACK_SIZE};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+};
+
+/// Register layout exposed to the debugger front end, in `g` packet order
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chippy.chip8">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+/// Number of registers in `TARGET_XML`
+const REGISTER_COUNT: usize = 21;
+
+/// Serves the GDB remote serial protocol to one client at a time
+pub struct GdbServer {
+    listener: TcpListener,
+    client: Option<Client>,
+}
+
+enum Packet {
+    Command(String),
+    /// Ctrl-C from the client
+    Interrupt,
+    /// Packet with a bad checksum, which the client resends
+    Corrupt(String),
+}
+
+struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    no_ack: bool,
+    /// Whether the client is waiting for a stop reply after resuming execution
+    waiting: bool,
+}
+
+impl GdbServer {
+    pub fn bind(address: SocketAddr) -> Result<Self, Chip8Error> {
+        let listener = TcpListener::bind(address).map_err(Chip8Error::Gdb)?;
+        listener.set_nonblocking(true).map_err(Chip8Error::Gdb)?;
+        Ok(Self {
+            listener,
+            client: None,
+        })
+    }
+
+    /// Accepts a client, handles its pending packets and reports when execution stops
+    pub(crate) fn poll(&mut self, cpu: &mut CPU, debugger: &mut Debugger) {
+        if self.client.is_none() {
+            if let Ok((stream, _)) = self.listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    // Execution is stopped while a client is attached until it resumes it
+                    debugger.halt();
+                    self.client = Some(Client {
+                        stream,
+                        buffer: Vec::new(),
+                        no_ack: false,
+                        waiting: false,
+                    });
+                }
+            }
+        }
+        if let Some(client) = &mut self.client {
+            if client.poll(cpu, debugger).is_err() {
+                // A disconnected client leaves the machine running
+                debugger.resume();
+                self.client = None;
+            }
+        }
+    }
+
+    /// Tells the client that the program exited
+    pub(crate) fn exited(&mut self) {
+        if let Some(client) = &mut self.client {
+            let _ = client.send(b"W00");
+        }
+    }
+}
+
+impl Client {
+    fn poll(&mut self, cpu: &mut CPU, debugger: &mut Debugger) -> io::Result<()> {
+        let mut chunk = [0; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+        while let Some(packet) = self.next_packet()? {
+            match packet {
+                Packet::Command(command) => self.handle(&command, cpu, debugger)?,
+                Packet::Interrupt => {
+                    debugger.halt();
+                    self.waiting = false;
+                    self.send(b"S02")?;
+                }
+                Packet::Corrupt(_) => {}
+            }
+        }
+        if self.waiting && !debugger.is_running() {
+            self.waiting = false;
+            self.stop_reply(debugger)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the next complete packet from the buffer, acknowledging it unless in no-ack mode
+    fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        let packet = take_packet(&mut self.buffer);
+        match packet {
+            // Checksums are not checked once acknowledgements are off
+            Some(Packet::Corrupt(data)) if self.no_ack => return Ok(Some(Packet::Command(data))),
+            Some(Packet::Corrupt(_)) => self.write(b"-")?,
+            Some(Packet::Command(_)) if !self.no_ack => self.write(b"+")?,
+            _ => {}
+        }
+        Ok(packet)
+    }
+
+    fn handle(&mut self, packet: &str, cpu: &mut CPU, debugger: &mut Debugger) -> io::Result<()> {
+        let Some(command) = packet.get(..1) else {
+            return self.send(b"");
+        };
+        let arguments = &packet[1..];
+        let reply = match command {
+            "?" => return self.stop_reply(debugger),
+            "g" => {
+                let mut reply = String::new();
+                for idx in 0..REGISTER_COUNT {
+                    reply.push_str(&read_register(cpu, idx).unwrap_or_default());
+                }
+                reply
+            }
+            "G" => {
+                let mut arguments = arguments;
+                for idx in 0..REGISTER_COUNT {
+                    let width = register_width(idx) * 2;
+                    if arguments.len() < width {
+                        break;
+                    }
+                    let (value, rest) = arguments.split_at(width);
+                    write_register(cpu, idx, value);
+                    arguments = rest;
+                }
+                "OK".into()
+            }
+            "p" => usize::from_str_radix(arguments, 16)
+                .ok()
+                .and_then(|idx| read_register(cpu, idx))
+                .unwrap_or_else(|| "E01".into()),
+            "P" => match arguments.split_once('=') {
+                Some((idx, value)) => match usize::from_str_radix(idx, 16) {
+                    Ok(idx) if write_register(cpu, idx, value) => "OK".into(),
+                    _ => "E01".into(),
+                },
+                None => "E01".into(),
+            },
+            "m" => match parse_memory_range(arguments) {
+                Some((address, length)) => {
+                    let mut reply = String::new();
+                    for offset in 0..length {
+                        reply.push_str(&format!("{:02x}", cpu.memory[address + offset]));
+                    }
+                    reply
+                }
+                None => "E01".into(),
+            },
+            "M" => match arguments.split_once(':') {
+                Some((range, data)) => match (parse_memory_range(range), decode_hex(data)) {
+                    (Some((address, length)), Some(bytes)) if bytes.len() == length => {
+                        for (offset, byte) in bytes.into_iter().enumerate() {
+                            cpu.memory[address + offset] = byte;
+                        }
+                        "OK".into()
+                    }
+                    _ => "E01".into(),
+                },
+                None => "E01".into(),
+            },
+            "Z" | "z" => {
+                let mut fields = arguments.split(',');
+                let kind = fields.next();
+                let address = fields
+                    .next()
+                    .and_then(|address| u16::from_str_radix(address, 16).ok());
+                match (kind, address) {
+                    (Some("0" | "1"), Some(address)) => {
+                        if command == "Z" {
+                            debugger.insert_breakpoint(address);
+                        } else {
+                            debugger.remove_breakpoint(address);
+                        }
+                        "OK".into()
+                    }
+                    _ => String::new(),
+                }
+            }
+            "c" | "s" => return self.resume(command == "s", arguments, cpu, debugger),
+            "v" => {
+                if arguments == "Cont?" {
+                    "vCont;c;s".into()
+                } else if let Some(actions) = arguments.strip_prefix("Cont;") {
+                    // Only one thread exists, so the first action applies to it
+                    let action = actions.split(';').next().unwrap_or_default();
+                    let step = action.starts_with('s');
+                    return self.resume(step, "", cpu, debugger);
+                } else {
+                    String::new()
+                }
+            }
+            "H" => "OK".into(),
+            "q" => {
+                if arguments.starts_with("Supported") {
+                    "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".into()
+                } else if let Some(read) = arguments.strip_prefix("Xfer:features:read:target.xml:")
+                {
+                    read_annex(TARGET_XML, read)
+                } else if arguments == "Attached" {
+                    "1".into()
+                } else if arguments == "C" {
+                    "QC1".into()
+                } else if arguments == "fThreadInfo" {
+                    "m1".into()
+                } else if arguments == "sThreadInfo" {
+                    "l".into()
+                } else {
+                    String::new()
+                }
+            }
+            "Q" => {
+                if arguments == "StartNoAckMode" {
+                    self.send(b"OK")?;
+                    self.no_ack = true;
+                    return Ok(());
+                }
+                String::new()
+            }
+            "D" => {
+                self.send(b"OK")?;
+                return Err(ErrorKind::ConnectionAborted.into());
+            }
+            "k" => return Err(ErrorKind::ConnectionAborted.into()),
+            _ => String::new(),
+        };
+        self.send(reply.as_bytes())
+    }
+
+    fn resume(
+        &mut self,
+        step: bool,
+        address: &str,
+        cpu: &mut CPU,
+        debugger: &mut Debugger,
+    ) -> io::Result<()> {
+        if !address.is_empty() {
+            // Addresses past the last instruction would fetch outside of memory
+            match u16::from_str_radix(address, 16) {
+                Ok(address) if address <= 4094 => cpu.program_counter = address,
+                _ => return self.send(b"E01"),
+            }
+        }
+        if debugger.is_faulted() {
+            return self.stop_reply(debugger);
+        }
+        if step {
+            debugger.halt();
+            debugger.step_into();
+        } else {
+            debugger.resume();
+        }
+        self.waiting = true;
+        Ok(())
+    }
+
+    fn stop_reply(&mut self, debugger: &Debugger) -> io::Result<()> {
+        // SIGILL after an illegal instruction, SIGTRAP otherwise
+        if debugger.is_faulted() {
+            self.send(b"S04")
+        } else {
+            self.send(b"S05")
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(format!("#{:02x}", sum(data)).as_bytes());
+        self.write(&packet)
+    }
+
+    fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.stream.write(data) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(len) => data = &data[len..],
+                Err(error) if error.kind() == ErrorKind::WouldBlock => std::thread::yield_now(),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes the next complete packet or interrupt from `buffer`, skipping anything before it
+fn take_packet(buffer: &mut Vec<u8>) -> Option<Packet> {
+    loop {
+        match buffer.first()? {
+            b'$' => break,
+            0x03 => {
+                buffer.remove(0);
+                return Some(Packet::Interrupt);
+            }
+            // Acknowledgements and noise between packets
+            _ => {
+                buffer.remove(0);
+            }
+        }
+    }
+    let end = buffer.iter().position(|&byte| byte == b'#')?;
+    if buffer.len() < end + 3 {
+        return None;
+    }
+    let packet: Vec<u8> = buffer.drain(..end + 3).collect();
+    let data = &packet[1..end];
+    let checksum = std::str::from_utf8(&packet[end + 1..])
+        .ok()
+        .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+    let command = String::from_utf8_lossy(data).into_owned();
+    if checksum == Some(sum(data)) {
+        Some(Packet::Command(command))
+    } else {
+        Some(Packet::Corrupt(command))
+    }
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn register_width(idx: usize) -> usize {
+    match idx {
+        16 | 17 => 2,
+        _ => 1,
+    }
+}
+
+/// Hex encodes a register in target byte order (little-endian)
+fn read_register(cpu: &CPU, idx: usize) -> Option<String> {
+    let value = match idx {
+        0..=15 => cpu.registers[idx] as u16,
+        16 => cpu.index_register,
+        17 => cpu.program_counter,
+        18 => cpu.stack_pointer as u16,
+        19 => cpu.delay_timer as u16,
+        20 => cpu.sound_timer as u16,
+        _ => return None,
+    };
+    let bytes = value.to_le_bytes();
+    Some(
+        bytes[..register_width(idx)]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect(),
+    )
+}
+
+fn write_register(cpu: &mut CPU, idx: usize, value: &str) -> bool {
+    let Some(bytes) = decode_hex(value) else {
+        return false;
+    };
+    if idx >= REGISTER_COUNT || bytes.len() != register_width(idx) {
+        return false;
+    }
+    let wide = u16::from_le_bytes([bytes[0], bytes.get(1).copied().unwrap_or(0)]);
+    match idx {
+        0..=15 => cpu.registers[idx] = bytes[0],
+        16 => cpu.index_register = wide.min(4095),
+        17 => cpu.program_counter = wide.min(4094),
+        18 => cpu.stack_pointer = bytes[0].min(STACK_SIZE as u8),
+        19 => cpu.delay_timer = bytes[0],
+        _ => cpu.sound_timer = bytes[0],
+    }
+    true
+}
+
+/// Parses `addr,length`, rejecting ranges outside of memory
+fn parse_memory_range(range: &str) -> Option<(usize, usize)> {
+    let (address, length) = range.split_once(',')?;
+    let address = usize::from_str_radix(address, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    (address.checked_add(length)? <= 4096).then_some((address, length))
+}
+
+/// Decodes pairs of hex digits, failing on an odd number of digits
+fn decode_hex(data: &str) -> Option<Vec<u8>> {
+    let digit = |byte: u8| (byte as char).to_digit(16).map(|digit| digit as u8);
+    data.as_bytes()
+        .chunks(2)
+        .map(|pair| match *pair {
+            [high, low] => Some(digit(high)? << 4 | digit(low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Answers a `qXfer` read of `offset,length` from `annex`
+fn read_annex(annex: &str, read: &str) -> String {
+    let Some((offset, length)) = read.split_once(',') else {
+        return "E01".into();
+    };
+    let (Ok(offset), Ok(length)) = (
+        usize::from_str_radix(offset, 16),
+        usize::from_str_radix(length, 16),
+    ) else {
+        return "E01".into();
+    };
+    if offset >= annex.len() {
+        return "l".into();
+    }
+    let end = offset.saturating_add(length).min(annex.len());
+    let marker = if end == annex.len() { 'l' } else { 'm' };
+    format!("{marker}{}", &annex[offset..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(packet: Option<Packet>) -> Option<String> {
+        match packet {
+            Some(Packet::Command(command)) => Some(command),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn packets_are_framed_and_checked() {
+        let mut buffer = b"+$g#67$m200,4#5f".to_vec();
+        assert_eq!(command(take_packet(&mut buffer)).as_deref(), Some("g"));
+        assert_eq!(command(take_packet(&mut buffer)).as_deref(), Some("m200,4"));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn incomplete_packets_stay_buffered() {
+        let mut buffer = b"$qSupported#3".to_vec();
+        assert!(take_packet(&mut buffer).is_none());
+        assert_eq!(buffer, b"$qSupported#3");
+        buffer.push(b'7');
+        assert_eq!(
+            command(take_packet(&mut buffer)).as_deref(),
+            Some("qSupported")
+        );
+    }
+
+    #[test]
+    fn bad_checksums_are_corrupt() {
+        let mut buffer = b"$g#00".to_vec();
+        assert!(matches!(take_packet(&mut buffer), Some(Packet::Corrupt(data)) if data == "g"));
+        let mut buffer = b"$g#zz".to_vec();
+        assert!(matches!(take_packet(&mut buffer), Some(Packet::Corrupt(_))));
+    }
+
+    #[test]
+    fn interrupts_between_packets() {
+        let mut buffer = b"-\x03$?#3f".to_vec();
+        assert!(matches!(take_packet(&mut buffer), Some(Packet::Interrupt)));
+        assert_eq!(command(take_packet(&mut buffer)).as_deref(), Some("?"));
+        assert!(take_packet(&mut buffer).is_none());
+    }
+
+    #[test]
+    fn hex_decoding() {
+        assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xFF, 0x7A]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+f"), None);
+    }
+
+    #[test]
+    fn memory_ranges_stay_in_memory() {
+        assert_eq!(parse_memory_range("200,10"), Some((0x200, 0x10)));
+        assert_eq!(parse_memory_range("ff0,10"), Some((0xFF0, 0x10)));
+        assert_eq!(parse_memory_range("ff0,11"), None);
+        assert_eq!(parse_memory_range("200"), None);
+    }
+
+    #[test]
+    fn annex_reads_in_chunks() {
+        assert_eq!(read_annex("abcdef", "0,4"), "mabcd");
+        assert_eq!(read_annex("abcdef", "4,4"), "lef");
+        assert_eq!(read_annex("abcdef", "0,6"), "labcdef");
+        assert_eq!(read_annex("abcdef", "6,4"), "l");
+        assert_eq!(read_annex("abcdef", "4"), "E01");
+        assert_eq!(read_annex("abcdef", "x,4"), "E01");
+        assert_eq!(read_annex("abcdef", "2,ffffffffffffffff"), "lcdef");
+    }
+
+    /// Frames `data` as a packet with its checksum
+    fn packet(data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        format!("${data}#{checksum:02x}")
+    }
+
+    /// Sends a packet from the client and polls the server until the reply arrives
+    fn exchange(
+        server: &mut GdbServer,
+        stream: &mut TcpStream,
+        cpu: &mut CPU,
+        debugger: &mut Debugger,
+        data: &str,
+    ) -> String {
+        stream.write_all(packet(data).as_bytes()).unwrap();
+        let mut received = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            server.poll(cpu, debugger);
+            match stream.read(&mut chunk) {
+                Ok(len) => received.extend_from_slice(&chunk[..len]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                Err(error) => panic!("{error}"),
+            }
+            let mut buffer = received.clone();
+            if let Some(Packet::Command(reply)) = take_packet(&mut buffer) {
+                assert!(received.starts_with(b"+"), "packet was not acknowledged");
+                return reply;
+            }
+        }
+    }
+
+    #[test]
+    fn loopback_client() {
+        let mut server = GdbServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.listener.local_addr().unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut cpu = CPU::with_sound(None);
+        let mut debugger = Debugger::new(false);
+        cpu.memory[0x200] = 0xA2;
+        cpu.memory[0x201] = 0xF0;
+        let mut send =
+            |data: &str| exchange(&mut server, &mut stream, &mut cpu, &mut debugger, data);
+
+        // Attaching halts execution
+        assert_eq!(send("?"), "S05");
+        assert_eq!(send("m200,2"), "a2f0");
+        assert_eq!(send("P0=2a"), "OK");
+        assert_eq!(send("p0"), "2a");
+        // A full stack can be restored, but not more
+        assert_eq!(send("P12=ff"), "OK");
+        assert_eq!(send("p12"), "10");
+        assert_eq!(send("M300,2:beef"), "OK");
+        assert_eq!(send("m300,2"), "beef");
+        assert_eq!(send("mfff,2"), "E01");
+        assert!(send("qXfer:features:read:target.xml:0,ffffffffffffffff").starts_with("l<?xml"));
+        let registers = send("g");
+        assert_eq!(&registers[..4], "2a00");
+        // I, then PC at 0x200 as little-endian 16-bit values
+        assert_eq!(&registers[32..40], "00000002");
+        assert_eq!(cpu.memory[0x300], 0xBE);
+    }
+}
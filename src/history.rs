@@ -0,0 +1,145 @@
+use crate::CPU;
+use std::collections::VecDeque;
+use uncheckedarray::UncheckedVec;
+
+/// Number of instructions that can be undone before the oldest are forgotten
+const HISTORY_CAPACITY: usize = 100_000;
+
+/// State overwritten by a single instruction, enough to undo it
+struct Record {
+    registers: [u8; 16],
+    program_counter: u16,
+    stack: [u16; 16],
+    stack_pointer: u8,
+    sound_timer: u8,
+    delay_timer: u8,
+    index_register: u16,
+    timer: usize,
+    cycles: u64,
+    awaited_key: Option<u8>,
+    /// Previous values of written memory bytes, in write order
+    memory: Vec<(u16, u8)>,
+    /// Previous values of toggled pixels, in write order
+    pixels: Vec<(u16, bool)>,
+    /// Dimensions and contents of a framebuffer that was cleared or replaced
+    framebuffer: Option<(usize, usize, Vec<bool>)>,
+}
+
+/// Undo log of executed instructions
+pub(crate) struct History {
+    records: VecDeque<Record>,
+}
+
+impl History {
+    pub(crate) fn new() -> Self {
+        Self {
+            records: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub(crate) fn memory(&mut self, address: usize, previous: u8) {
+        if let Some(record) = self.records.back_mut() {
+            record.memory.push((address as u16, previous));
+        }
+    }
+
+    pub(crate) fn pixel(&mut self, idx: usize, previous: bool) {
+        if let Some(record) = self.records.back_mut() {
+            record.pixels.push((idx as u16, previous));
+        }
+    }
+
+    pub(crate) fn framebuffer(&mut self, width: usize, height: usize, previous: Vec<bool>) {
+        if let Some(record) = self.records.back_mut() {
+            if record.framebuffer.is_none() {
+                record.framebuffer = Some((width, height, previous));
+            }
+        }
+    }
+}
+
+impl CPU {
+    /// Starts a new undo record for the instruction about to be executed
+    pub(crate) fn record(&mut self) {
+        let Some(history) = &mut self.history else {
+            return;
+        };
+        if history.records.len() == HISTORY_CAPACITY {
+            history.records.pop_front();
+        }
+        let mut registers = [0; 16];
+        let mut stack = [0; 16];
+        for idx in 0..16 {
+            registers[idx] = self.registers[idx];
+            stack[idx] = self.stack[idx];
+        }
+        history.records.push_back(Record {
+            registers,
+            program_counter: self.program_counter,
+            stack,
+            stack_pointer: self.stack_pointer,
+            sound_timer: self.sound_timer,
+            delay_timer: self.delay_timer,
+            index_register: self.index_register,
+            timer: self.timer,
+            cycles: self.cycles,
+            awaited_key: self.awaited_key,
+            memory: Vec::new(),
+            pixels: Vec::new(),
+            framebuffer: None,
+        });
+    }
+
+    /// Saves the whole framebuffer before it is cleared or replaced
+    pub(crate) fn record_framebuffer(&mut self) {
+        if self.history.is_none() {
+            return;
+        }
+        let previous = (0..self.display_width * self.display_height)
+            .map(|idx| self.framebuffer[idx])
+            .collect();
+        if let Some(history) = &mut self.history {
+            history.framebuffer(self.display_width, self.display_height, previous);
+        }
+    }
+
+    /// Reverts the most recently executed instruction, returning `false` if there is nothing to undo
+    pub(crate) fn undo(&mut self) -> bool {
+        let Some(record) = self
+            .history
+            .as_mut()
+            .and_then(|history| history.records.pop_back())
+        else {
+            return false;
+        };
+        for idx in 0..16 {
+            self.registers[idx] = record.registers[idx];
+            self.stack[idx] = record.stack[idx];
+        }
+        self.program_counter = record.program_counter;
+        self.stack_pointer = record.stack_pointer;
+        self.sound_timer = record.sound_timer;
+        self.delay_timer = record.delay_timer;
+        self.index_register = record.index_register;
+        self.timer = record.timer;
+        self.cycles = record.cycles;
+        self.awaited_key = record.awaited_key;
+        // Writes are reverted newest first so that repeated writes restore the oldest value
+        for (address, previous) in record.memory.into_iter().rev() {
+            self.memory[address as usize] = previous;
+        }
+        for (idx, previous) in record.pixels.into_iter().rev() {
+            self.framebuffer[idx as usize] = previous;
+        }
+        if let Some((width, height, previous)) = record.framebuffer {
+            self.display_width = width;
+            self.display_height = height;
+            self.framebuffer = unsafe { UncheckedVec::new(previous) };
+        }
+        true
+    }
+}
@@ -0,0 +1,396 @@
+use crate::CPU;
+use egui::{Button, Color32, Grid, RichText, Ui};
+use macroquad::prelude::*;
+use serde::{Deserialize, Deserializer};
+use std::str::FromStr;
+
+/// Host keys that can be bound to the keypad
+const HOST_KEYS: [KeyCode; 117] = [
+    KeyCode::Space,
+    KeyCode::Apostrophe,
+    KeyCode::Comma,
+    KeyCode::Minus,
+    KeyCode::Period,
+    KeyCode::Slash,
+    KeyCode::Key0,
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+    KeyCode::Semicolon,
+    KeyCode::Equal,
+    KeyCode::A,
+    KeyCode::B,
+    KeyCode::C,
+    KeyCode::D,
+    KeyCode::E,
+    KeyCode::F,
+    KeyCode::G,
+    KeyCode::H,
+    KeyCode::I,
+    KeyCode::J,
+    KeyCode::K,
+    KeyCode::L,
+    KeyCode::M,
+    KeyCode::N,
+    KeyCode::O,
+    KeyCode::P,
+    KeyCode::Q,
+    KeyCode::R,
+    KeyCode::S,
+    KeyCode::T,
+    KeyCode::U,
+    KeyCode::V,
+    KeyCode::W,
+    KeyCode::X,
+    KeyCode::Y,
+    KeyCode::Z,
+    KeyCode::LeftBracket,
+    KeyCode::Backslash,
+    KeyCode::RightBracket,
+    KeyCode::GraveAccent,
+    KeyCode::Enter,
+    KeyCode::Tab,
+    KeyCode::Backspace,
+    KeyCode::Insert,
+    KeyCode::Delete,
+    KeyCode::Right,
+    KeyCode::Left,
+    KeyCode::Down,
+    KeyCode::Up,
+    KeyCode::PageUp,
+    KeyCode::PageDown,
+    KeyCode::Home,
+    KeyCode::End,
+    KeyCode::CapsLock,
+    KeyCode::ScrollLock,
+    KeyCode::NumLock,
+    KeyCode::PrintScreen,
+    KeyCode::Pause,
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+    KeyCode::F7,
+    KeyCode::F8,
+    KeyCode::F9,
+    KeyCode::F10,
+    KeyCode::F11,
+    KeyCode::F12,
+    KeyCode::F13,
+    KeyCode::F14,
+    KeyCode::F15,
+    KeyCode::F16,
+    KeyCode::F17,
+    KeyCode::F18,
+    KeyCode::F19,
+    KeyCode::F20,
+    KeyCode::F21,
+    KeyCode::F22,
+    KeyCode::F23,
+    KeyCode::F24,
+    KeyCode::F25,
+    KeyCode::Kp0,
+    KeyCode::Kp1,
+    KeyCode::Kp2,
+    KeyCode::Kp3,
+    KeyCode::Kp4,
+    KeyCode::Kp5,
+    KeyCode::Kp6,
+    KeyCode::Kp7,
+    KeyCode::Kp8,
+    KeyCode::Kp9,
+    KeyCode::KpDecimal,
+    KeyCode::KpDivide,
+    KeyCode::KpMultiply,
+    KeyCode::KpSubtract,
+    KeyCode::KpAdd,
+    KeyCode::KpEnter,
+    KeyCode::KpEqual,
+    KeyCode::LeftShift,
+    KeyCode::LeftControl,
+    KeyCode::LeftAlt,
+    KeyCode::LeftSuper,
+    KeyCode::RightShift,
+    KeyCode::RightControl,
+    KeyCode::RightAlt,
+    KeyCode::RightSuper,
+    KeyCode::Menu,
+];
+
+/// Host key given by its name, such as `A`, `Key1`, `Up` or `Kp0`. Digits may be written without
+/// the `Key` prefix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostKey(pub KeyCode);
+
+fn key_name(key: KeyCode) -> String {
+    format!("{key:?}")
+}
+
+impl FromStr for HostKey {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let name = match name {
+            digit if digit.len() == 1 && digit.as_bytes()[0].is_ascii_digit() => {
+                format!("Key{digit}")
+            }
+            name => name.to_string(),
+        };
+        HOST_KEYS
+            .into_iter()
+            .find(|&key| key_name(key).eq_ignore_ascii_case(&name))
+            .map(Self)
+            .ok_or_else(|| format!("unknown key: {name}"))
+    }
+}
+
+impl<'de> Deserialize<'de> for HostKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// CHIP-8 keypad key from `0` to `f`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KeypadKey(pub u8);
+
+impl FromStr for KeypadKey {
+    type Err = String;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        match u8::from_str_radix(key, 16) {
+            Ok(value) if key.len() == 1 => Ok(Self(value)),
+            _ => Err(format!("invalid keypad key: {key} (expected 0-f)")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for KeypadKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        key.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Host keys for one keypad key, written as `5=W,Up` on the command line
+#[derive(Clone, Debug)]
+pub struct KeyBinding {
+    pub key: KeypadKey,
+    pub host_keys: Vec<HostKey>,
+}
+
+impl FromStr for KeyBinding {
+    type Err = String;
+
+    fn from_str(binding: &str) -> Result<Self, Self::Err> {
+        let (key, host_keys) = binding
+            .split_once('=')
+            .ok_or_else(|| format!("invalid key binding: {binding} (expected KEY=HOSTKEY,...)"))?;
+        Ok(Self {
+            key: key.parse()?,
+            host_keys: host_keys
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Host keys bound to each keypad key
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyMap {
+    pub keys: [Vec<KeyCode>; 16],
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let layout = [
+            KeyCode::Key1,
+            KeyCode::Key2,
+            KeyCode::Key3,
+            KeyCode::Key4,
+            KeyCode::Q,
+            KeyCode::W,
+            KeyCode::E,
+            KeyCode::R,
+            KeyCode::A,
+            KeyCode::S,
+            KeyCode::D,
+            KeyCode::F,
+            KeyCode::Z,
+            KeyCode::X,
+            KeyCode::C,
+            KeyCode::V,
+        ];
+        Self {
+            keys: layout.map(|key| vec![key]),
+        }
+    }
+}
+
+/// Keypad keys in the layout of the COSMAC VIP keypad
+const KEYPAD_LAYOUT: [[usize; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+/// Clickable keypad window for players who don't know the keyboard layout
+#[derive(Clone, Debug, Default)]
+pub struct Keypad {
+    pub visible: bool,
+    /// Text shown on each key, describing what it does in the current ROM
+    pub labels: [String; 16],
+}
+
+impl Keypad {
+    pub fn set_labels<'a>(
+        &mut self,
+        labels: impl IntoIterator<Item = (&'a KeypadKey, &'a String)>,
+    ) {
+        for (key, label) in labels {
+            self.labels[key.0 as usize] = label.clone();
+        }
+    }
+}
+
+impl KeyMap {
+    /// Replaces the host keys of each keypad key in `bindings`
+    pub fn bind<'a>(
+        &mut self,
+        bindings: impl IntoIterator<Item = (&'a KeypadKey, &'a Vec<HostKey>)>,
+    ) {
+        for (key, host_keys) in bindings {
+            self.keys[key.0 as usize] = host_keys.iter().map(|host_key| host_key.0).collect();
+        }
+    }
+
+    pub(crate) fn is_down(&self, key: usize) -> bool {
+        self.keys[key].iter().any(|&host_key| is_key_down(host_key))
+    }
+
+    /// Remapping table, where `capture` is the keypad key waiting for a host key to be pressed
+    pub(crate) fn ui(&mut self, ui: &mut Ui, capture: &mut Option<usize>) {
+        Grid::new("keys").striped(true).show(ui, |ui| {
+            for (idx, host_keys) in self.keys.iter_mut().enumerate() {
+                ui.label(format!("{idx:X}"));
+                let names: Vec<String> = host_keys.iter().map(|&key| key_name(key)).collect();
+                ui.label(if names.is_empty() {
+                    String::from("-")
+                } else {
+                    names.join(", ")
+                });
+                if *capture == Some(idx) {
+                    ui.label("Press a key (Escape to cancel)");
+                    if let Some(key) = get_last_key_pressed() {
+                        if HOST_KEYS.contains(&key) && !host_keys.contains(&key) {
+                            host_keys.push(key);
+                        }
+                        *capture = None;
+                    }
+                } else if ui.button("Add").clicked() {
+                    *capture = Some(idx);
+                }
+                if ui.button("Clear").clicked() {
+                    host_keys.clear();
+                }
+                ui.end_row();
+            }
+        });
+        if ui.button("Reset Keys").clicked() {
+            *self = KeyMap::default();
+            *capture = None;
+        }
+    }
+}
+
+impl CPU {
+    pub(crate) fn poll_keys(&mut self) {
+        for idx in 0..16 {
+            let down = self.settings.keys.is_down(idx) || self.keypad_pressed[idx];
+            self.pressed_keys[idx] = down && !self.keys[idx];
+            self.keys[idx] = down;
+        }
+    }
+
+    pub(crate) fn keypad_window(&mut self, egui_ctx: &egui::Context) {
+        self.keypad_pressed = [false; 16];
+        let keypad = &mut self.settings.keypad;
+        egui::Window::new("Keypad")
+            .open(&mut keypad.visible)
+            .resizable(false)
+            .show(egui_ctx, |ui| {
+                Grid::new("keypad").show(ui, |ui| {
+                    for row in KEYPAD_LAYOUT {
+                        for key in row {
+                            let text = match keypad.labels[key].as_str() {
+                                "" => RichText::new(format!("{key:X}")).heading(),
+                                label => RichText::new(format!("{key:X}\n{label}")),
+                            };
+                            let mut button = Button::new(text);
+                            if self.keys[key] {
+                                button = button.fill(Color32::DARK_GREEN);
+                            }
+                            // Held for as long as the pointer or touch stays down
+                            self.keypad_pressed[key] = ui
+                                .add_sized([48.0, 48.0], button)
+                                .is_pointer_button_down_on();
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keypad_keys_are_single_hex_digits() {
+        assert_eq!("0".parse(), Ok(KeypadKey(0)));
+        assert_eq!("a".parse(), Ok(KeypadKey(0xA)));
+        assert_eq!("F".parse(), Ok(KeypadKey(0xF)));
+        assert!("10".parse::<KeypadKey>().is_err());
+        assert!("g".parse::<KeypadKey>().is_err());
+        assert!("".parse::<KeypadKey>().is_err());
+    }
+
+    #[test]
+    fn host_keys_by_name() {
+        assert_eq!("W".parse(), Ok(HostKey(KeyCode::W)));
+        assert_eq!("up".parse(), Ok(HostKey(KeyCode::Up)));
+        assert_eq!("5".parse(), Ok(HostKey(KeyCode::Key5)));
+        assert_eq!("Key5".parse(), Ok(HostKey(KeyCode::Key5)));
+        assert!("Nope".parse::<HostKey>().is_err());
+    }
+
+    #[test]
+    fn key_bindings() {
+        let binding: KeyBinding = "5=W,Up".parse().unwrap();
+        assert_eq!(binding.key, KeypadKey(5));
+        assert_eq!(
+            binding.host_keys,
+            [HostKey(KeyCode::W), HostKey(KeyCode::Up)]
+        );
+        // An empty list unbinds the key
+        let binding: KeyBinding = "c=".parse().unwrap();
+        assert_eq!(binding.key, KeypadKey(0xC));
+        assert!(binding.host_keys.is_empty());
+        assert!("5".parse::<KeyBinding>().is_err());
+        assert!("x=W".parse::<KeyBinding>().is_err());
+        assert!("5=W,Nope".parse::<KeyBinding>().is_err());
+    }
+}
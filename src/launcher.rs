@@ -0,0 +1,262 @@
+use crate::{
+    config::RomConfig,
+    database::lookup_rom,
+    quirks::Platform,
+    rom::{archive_files, is_archive, RomFile, RomLocation},
+    CPU,
+};
+use egui::{Color32, ColorImage, RichText, ScrollArea, TextureHandle};
+use macroquad::prelude::*;
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Instructions executed before a thumbnail is taken, enough for most ROMs to draw a title screen
+const THUMBNAIL_CYCLES: usize = 20_000;
+const THUMBNAIL_SCALE: u32 = 4;
+
+struct Entry {
+    location: RomLocation,
+    name: String,
+    size: usize,
+    hash: String,
+    /// Database entry for the ROM, if it is known, or the options embedded in it
+    rom: Option<RomConfig>,
+    /// Platform implied by the file extension or embedded options
+    platform: Option<Platform>,
+    /// Thumbnail waiting to be uploaded to egui
+    image: Option<ColorImage>,
+    thumbnail: Option<TextureHandle>,
+    generated: bool,
+}
+
+impl Entry {
+    fn title(&self) -> &str {
+        self.rom
+            .as_ref()
+            .and_then(|rom| rom.title.as_deref())
+            .unwrap_or(&self.name)
+    }
+}
+
+/// Lists the ROMs in a directory or zip archive for the player to choose from, including those
+/// inside archives in the directory
+pub struct Launcher {
+    source: PathBuf,
+    entries: Vec<Entry>,
+    selected: Option<usize>,
+    error: Option<String>,
+}
+
+impl Launcher {
+    pub fn new(source: impl Into<PathBuf>) -> Self {
+        Self {
+            source: source.into(),
+            entries: Vec::new(),
+            selected: None,
+            error: None,
+        }
+    }
+
+    /// Shows `error` the next time the launcher is opened, after `location` failed to run
+    pub fn show_error(&mut self, location: &RomLocation, error: impl Display) {
+        self.error = Some(format!("{location}: {error:#}"));
+    }
+
+    fn scan(&mut self) {
+        let selected = self
+            .selected
+            .and_then(|idx| self.entries.get(idx))
+            .map(|entry| entry.location.clone());
+        let mut paths: Vec<PathBuf> = if self.source.is_file() {
+            vec![self.source.clone()]
+        } else {
+            fs::read_dir(&self.source)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file())
+                .collect()
+        };
+        paths.sort();
+        // Each archive is read and opened once, and all of its ROMs extracted from that
+        let mut entries = Vec::new();
+        for path in paths {
+            if is_archive(&path) {
+                let files = fs::read(&path)
+                    .ok()
+                    .and_then(|data| archive_files(&data).ok())
+                    .unwrap_or_default();
+                entries.extend(files.into_iter().filter_map(|(entry, data)| {
+                    let file = RomFile::parse(Path::new(&entry), data).ok()?;
+                    let location = RomLocation {
+                        path: path.clone(),
+                        entry: Some(entry),
+                    };
+                    scan_entry(location, file)
+                }));
+            } else {
+                let file = fs::read(&path)
+                    .ok()
+                    .and_then(|data| RomFile::parse(&path, data).ok());
+                entries.extend(file.and_then(|file| scan_entry(RomLocation::file(path), file)));
+            }
+        }
+        self.entries = entries;
+        self.selected = selected
+            .and_then(|selected| {
+                self.entries
+                    .iter()
+                    .position(|entry| entry.location == selected)
+            })
+            .or(if self.entries.is_empty() {
+                None
+            } else {
+                Some(0)
+            });
+    }
+
+    /// Shows the launcher until a ROM is chosen, or returns `None` if the window is closed
+    pub async fn choose(&mut self) -> Option<RomLocation> {
+        prevent_quit();
+        self.scan();
+        loop {
+            if is_quit_requested() {
+                return None;
+            }
+            let mut chosen = None;
+            if !self.entries.is_empty() {
+                let last = self.entries.len() - 1;
+                let selected = self.selected.unwrap_or(0);
+                if is_key_pressed(KeyCode::Down) {
+                    self.selected = Some((selected + 1).min(last));
+                }
+                if is_key_pressed(KeyCode::Up) {
+                    self.selected = Some(selected.saturating_sub(1));
+                }
+                if is_key_pressed(KeyCode::Enter) {
+                    chosen = Some(self.entries[selected].location.clone());
+                }
+            }
+            if let Some(entry) = self.selected.and_then(|idx| self.entries.get_mut(idx)) {
+                if !entry.generated {
+                    entry.generated = true;
+                    entry.image = thumbnail(&entry.location).await;
+                }
+            }
+            clear_background(BLACK);
+            egui_macroquad::ui(|egui_ctx| {
+                if let Some(path) = self.ui(egui_ctx) {
+                    chosen = Some(path);
+                }
+            });
+            egui_macroquad::draw();
+            next_frame().await;
+            if chosen.is_some() {
+                self.error = None;
+                return chosen;
+            }
+        }
+    }
+
+    fn ui(&mut self, egui_ctx: &egui::Context) -> Option<RomLocation> {
+        let mut chosen = None;
+        let mut rescan = false;
+        egui::SidePanel::left("roms").show(egui_ctx, |ui| {
+            ui.heading("ROMs");
+            ui.small(self.source.display().to_string());
+            ui.separator();
+            ScrollArea::vertical().show(ui, |ui| {
+                for (idx, entry) in self.entries.iter().enumerate() {
+                    let response = ui.selectable_label(self.selected == Some(idx), entry.title());
+                    if response.clicked() {
+                        self.selected = Some(idx);
+                    }
+                    if response.double_clicked() {
+                        chosen = Some(entry.location.clone());
+                    }
+                }
+            });
+        });
+        egui::CentralPanel::default().show(egui_ctx, |ui| {
+            if let Some(error) = &self.error {
+                ui.colored_label(Color32::LIGHT_RED, error);
+                ui.separator();
+            }
+            let Some(entry) = self.selected.and_then(|idx| self.entries.get_mut(idx)) else {
+                ui.label(format!("No ROMs found in {}", self.source.display()));
+                rescan = ui.button("Rescan").clicked();
+                return;
+            };
+            if let Some(image) = entry.image.take() {
+                entry.thumbnail = Some(ui.ctx().load_texture(&entry.name, image));
+            }
+            ui.heading(entry.title());
+            if let Some(author) = entry.rom.as_ref().and_then(|rom| rom.author.as_ref()) {
+                ui.label(format!("By {author}"));
+            }
+            let platform = entry.rom.as_ref().and_then(|rom| rom.platform);
+            if let Some(platform) = platform.or(entry.platform) {
+                ui.label(platform.name());
+            }
+            ui.label(format!("{} ({} bytes)", entry.name, entry.size));
+            ui.small(format!("SHA-1 {}", entry.hash));
+            if let Some(thumbnail) = &entry.thumbnail {
+                ui.image(thumbnail, thumbnail.size_vec2());
+            }
+            if let Some(labels) = entry.rom.as_ref().map(|rom| &rom.labels) {
+                for (key, label) in labels {
+                    ui.label(RichText::new(format!("{:X}  {label}", key.0)).monospace());
+                }
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Start").clicked() {
+                    chosen = Some(entry.location.clone());
+                }
+                rescan = ui.button("Rescan").clicked();
+            });
+        });
+        if rescan {
+            self.scan();
+        }
+        chosen
+    }
+}
+
+/// Describes the ROM read from `location` for listing
+fn scan_entry(location: RomLocation, file: RomFile) -> Option<Entry> {
+    let hash = sha1_smol::Sha1::from(&file.data).digest().to_string();
+    let name = match &location.entry {
+        Some(entry) => entry.rsplit('/').next().unwrap_or(entry).to_string(),
+        None => location.path.file_name()?.to_string_lossy().into_owned(),
+    };
+    Some(Entry {
+        name,
+        size: file.data.len(),
+        rom: lookup_rom(&hash).ok().flatten().or(file.options),
+        platform: file.platform,
+        hash,
+        location,
+        image: None,
+        thumbnail: None,
+        generated: false,
+    })
+}
+
+/// Runs the ROM at `location` for a while without input and captures the display
+async fn thumbnail(location: &RomLocation) -> Option<ColorImage> {
+    let mut cpu = CPU::new().await;
+    cpu.load_location(location).await.ok()?;
+    // Whatever was drawn before a fault is still worth showing
+    let _ = cpu.run_headless(THUMBNAIL_CYCLES);
+    let image = cpu.frame_image(THUMBNAIL_SCALE);
+    let size = [image.width() as usize, image.height() as usize];
+    let pixels = image
+        .pixels()
+        .map(|pixel| Color32::from_rgb(pixel[0], pixel[1], pixel[2]))
+        .collect();
+    Some(ColorImage { size, pixels })
+}
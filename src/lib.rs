@@ -1,15 +1,69 @@
-use egui::{Align, Color32, ScrollArea};
 use macroquad::{
-    audio::{self, Sound},
+    audio::{self, PlaySoundParams, Sound},
     prelude::*,
     rand::{gen_range, srand},
 };
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 use thiserror::Error;
 use uncheckedarray::{UncheckedArray, UncheckedVec};
 
+mod cheats;
+mod config;
+mod coverage;
+mod database;
+mod debugger;
+mod gdb;
+mod history;
+mod input;
+mod launcher;
+mod octo;
+mod patch;
+mod profiler;
+mod quirks;
+mod recorder;
+mod renderer;
+mod rom;
+mod screenshot;
+mod settings;
+mod trace;
+
+use cheats::MemorySearch;
+pub use cheats::{Cheat, CheatTarget, Cheats};
+pub use config::{Config, RomConfig};
+pub use coverage::Coverage;
+pub use database::{lookup_rom, RomInfo};
+use debugger::Debugger;
+pub use gdb::GdbServer;
+use history::History;
+pub use input::{HostKey, KeyBinding, KeyMap, Keypad, KeypadKey};
+pub use launcher::Launcher;
+pub use patch::find_patches;
+use profiler::Profiler;
+pub use quirks::{KeyWait, Platform, Quirks};
+use recorder::Recorder;
+pub use recorder::RecordingFormat;
+use renderer::Renderer;
+use rom::RomFile;
+pub use rom::{is_archive, RomLocation};
+use settings::MenuAction;
+pub use settings::{
+    HexColour, Multiplier, Palette, Persistence, PersistenceMode, Scaling, ScalingMode,
+    Screenshots, Settings, Theme,
+};
+pub use trace::{TraceFormat, Tracer};
+
 const BEEP_SOUND: &[u8] = include_bytes!("../assets/sound.wav");
 
+/// Return addresses the stack holds, beyond which calls fault
+const STACK_SIZE: usize = 16;
+/// Bytes of memory, beyond which accesses fault
+const MEMORY_SIZE: usize = 4096;
+
 const FONT_SET: [u8; 80] = [
     0xf0, 0x90, 0x90, 0x90, 0xf0, 0x20, 0x60, 0x20, 0x20, 0x70, 0xf0, 0x10, 0xf0, 0x80, 0xf0, 0xf0,
     0x10, 0xf0, 0x10, 0xf0, 0x90, 0x90, 0xf0, 0x10, 0x10, 0xf0, 0x80, 0xf0, 0x10, 0xf0, 0xf0, 0x80,
@@ -18,274 +72,674 @@ const FONT_SET: [u8; 80] = [
     0xf0, 0xe0, 0x90, 0x90, 0x90, 0xe0, 0xf0, 0x80, 0xf0, 0x80, 0xf0, 0xf0, 0x80, 0xf0, 0x80, 0x80,
 ];
 
-const KEY_MAP: [KeyCode; 16] = [
-    KeyCode::Key1,
-    KeyCode::Key2,
-    KeyCode::Key3,
-    KeyCode::Key4,
-    KeyCode::Q,
-    KeyCode::W,
-    KeyCode::E,
-    KeyCode::R,
-    KeyCode::A,
-    KeyCode::S,
-    KeyCode::D,
-    KeyCode::F,
-    KeyCode::Z,
-    KeyCode::X,
-    KeyCode::C,
-    KeyCode::V,
-];
-
 pub struct CPU {
     registers: UncheckedArray<16, u8>,
     program_counter: u16,
     memory: UncheckedArray<4096, u8>,
-    stack: UncheckedArray<16, u16>,
+    stack: UncheckedArray<STACK_SIZE, u16>,
     stack_pointer: u8,
     sound_timer: u8,
     delay_timer: u8,
     index_register: u16,
     framebuffer: UncheckedVec<bool>,
-    sound: Sound,
+    /// Beeper sound, which is missing only when the CPU is built without an audio context
+    sound: Option<Sound>,
     keys: UncheckedArray<16, bool>,
+    /// Keys held down on the on-screen keypad
+    keypad_pressed: [bool; 16],
+    /// Ignores input and sound, as when generating launcher thumbnails
+    headless: bool,
+    /// Keys that went down since the previous poll
+    pressed_keys: [bool; 16],
+    /// Key pressed during `Fx0A` that has not been released yet
+    awaited_key: Option<u8>,
+    quirks: Quirks,
     display_width: usize,
     display_height: usize,
+    /// Instructions executed since the timers last ticked
+    timer: usize,
+    history: Option<History>,
+    cycles: u64,
+    tracer: Option<Tracer>,
+    gdb: Option<GdbServer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    rom_size: usize,
+    /// File name of the ROM without its extension, used to name screenshots
+    rom_name: String,
+    rom_info: RomInfo,
+    /// Contents of the ROM file, copied into memory on reset
+    rom: Vec<u8>,
+    rom_location: Option<RomLocation>,
+    rom_options: Option<RomConfig>,
+    /// File names and contents of the patches applied to the ROM, in order
+    patches: Vec<(String, Vec<u8>)>,
+    cheats: Cheats,
+    search: MemorySearch,
+    /// Reload the ROM whenever the file changes
+    watch: bool,
+    settings: Settings,
+    exit_screenshot: Option<PathBuf>,
+    recorder: Option<Recorder>,
+    /// Error that stopped the recording mid-frame, reported by the run loop
+    recording_error: Option<Chip8Error>,
+    /// Multiplier in effect this frame, taking the fast-forward key into account
+    rate: Multiplier,
+    /// Host time the beeper last started, so that beeps are not stacked up at high rates
+    last_beep: f64,
 }
 
 impl CPU {
     pub async fn new() -> Self {
+        let sound = unsafe {
+            audio::load_sound_from_bytes(BEEP_SOUND)
+                .await
+                .unwrap_unchecked()
+        };
+        Self::with_sound(Some(sound))
+    }
+
+    fn with_sound(sound: Option<Sound>) -> Self {
         srand(macroquad::miniquad::date::now() as _);
         Self {
             registers: unsafe { UncheckedArray::new([0; 16]) },
             program_counter: 0x200,
             memory: unsafe { UncheckedArray::new([0; 4096]) },
-            stack: unsafe { UncheckedArray::new([0; 16]) },
+            stack: unsafe { UncheckedArray::new([0; STACK_SIZE]) },
             stack_pointer: 0,
             sound_timer: 0,
             delay_timer: 0,
             index_register: 0,
             framebuffer: unsafe { UncheckedVec::new(vec![false; 64 * 32]) },
-            sound: unsafe {
-                audio::load_sound_from_bytes(BEEP_SOUND)
-                    .await
-                    .unwrap_unchecked()
-            },
+            sound,
             keys: unsafe { UncheckedArray::new([false; 16]) },
+            keypad_pressed: [false; 16],
+            headless: false,
+            pressed_keys: [false; 16],
+            awaited_key: None,
+            quirks: Quirks::default(),
             display_width: 64,
             display_height: 32,
+            timer: 0,
+            history: None,
+            cycles: 0,
+            tracer: None,
+            gdb: None,
+            profiler: None,
+            coverage: None,
+            rom_size: 0,
+            rom_name: String::from("chippy"),
+            rom_info: RomInfo::default(),
+            rom: Vec::new(),
+            rom_location: None,
+            rom_options: None,
+            patches: Vec::new(),
+            cheats: Cheats::default(),
+            search: MemorySearch::default(),
+            watch: false,
+            settings: Settings::default(),
+            exit_screenshot: None,
+            recorder: None,
+            recording_error: None,
+            rate: Multiplier::NORMAL,
+            last_beep: 0.0,
         }
     }
 
+    /// Loads a raw binary, or the program in an Octo cartridge GIF
     pub async fn load(&mut self, path: &str) -> Result<(), Chip8Error> {
+        self.load_location(&RomLocation::file(path)).await
+    }
+
+    /// Loads a ROM from a file or from an entry in a zip archive
+    pub async fn load_location(&mut self, location: &RomLocation) -> Result<(), Chip8Error> {
+        let file = RomFile::read(location).await?;
+        self.rom_name = location.name();
+        self.rom_location = Some(location.clone());
+        self.rom_info = RomInfo {
+            hash: sha1_smol::Sha1::from(&file.data).digest().to_string(),
+            platform: file.platform,
+            ..RomInfo::default()
+        };
+        self.rom = file.data;
+        self.rom_options = file.options;
+        self.patches.clear();
+        self.reset();
+        Ok(())
+    }
+
+    /// Applies an IPS or BPS patch to the ROM, now and whenever it is reloaded. The ROM keeps
+    /// the hash of the unpatched file.
+    pub async fn add_patch(&mut self, path: &Path) -> Result<(), Chip8Error> {
+        let name = path.file_name().map_or_else(
+            || path.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        let patch = load_file(&path.to_string_lossy()).await?;
+        self.rom = patch::apply(&self.rom, &patch).map_err(|message| Chip8Error::Patch {
+            name: name.clone(),
+            message,
+        })?;
+        self.patches.push((name, patch));
+        self.reset();
+        Ok(())
+    }
+
+    /// Reads the ROM from disk again and resets
+    pub async fn reload(&mut self) -> Result<(), Chip8Error> {
+        let Some(location) = &self.rom_location else {
+            self.reset();
+            return Ok(());
+        };
+        let mut rom = RomFile::read(location).await?.data;
+        self.rom_info.hash = sha1_smol::Sha1::from(&rom).digest().to_string();
+        for (name, patch) in &self.patches {
+            rom = patch::apply(&rom, patch).map_err(|message| Chip8Error::Patch {
+                name: name.clone(),
+                message,
+            })?;
+        }
+        self.rom = rom;
+        self.reset();
+        Ok(())
+    }
+
+    /// Returns the machine to its power-on state with the ROM loaded, keeping settings and
+    /// attached tools
+    pub fn reset(&mut self) {
         const MEMORY_START: usize = 0x200;
+        for idx in 0..16 {
+            self.registers[idx] = 0;
+            self.stack[idx] = 0;
+            self.keys[idx] = false;
+        }
+        for idx in 0..4096 {
+            self.memory[idx] = 0;
+        }
         for (idx, f) in FONT_SET.into_iter().enumerate() {
             self.memory[idx] = f;
         }
-        let data = load_file(path).await?;
-        let mut bytes = [0; (4096 - MEMORY_START)];
-        for (idx, byte) in data.into_iter().enumerate() {
-            bytes[idx] = byte;
-        }
-        for (idx, byte) in bytes.into_iter().enumerate() {
+        self.rom_size = self.rom.len().min(4096 - MEMORY_START);
+        for (idx, &byte) in self.rom[..self.rom_size].iter().enumerate() {
             self.memory[MEMORY_START + idx] = byte;
         }
+        self.program_counter = MEMORY_START as u16;
+        self.stack_pointer = 0;
+        self.sound_timer = 0;
+        self.delay_timer = 0;
+        self.index_register = 0;
+        self.display_width = 64;
+        self.display_height = 32;
+        self.framebuffer = unsafe { UncheckedVec::new(vec![false; 64 * 32]) };
+        self.pressed_keys = [false; 16];
+        self.awaited_key = None;
+        self.timer = 0;
+        self.cycles = 0;
+        if self.history.is_some() {
+            self.history = Some(History::new());
+        }
+    }
+
+    /// File name of the loaded ROM without its extension
+    pub fn rom_name(&self) -> &str {
+        &self.rom_name
+    }
+
+    pub fn rom_info(&self) -> &RomInfo {
+        &self.rom_info
+    }
+
+    /// Options embedded in the ROM file, as in Octo cartridges
+    pub fn embedded_options(&self) -> Option<RomConfig> {
+        self.rom_options.clone()
+    }
+
+    pub fn set_rom_info(&mut self, info: RomInfo) {
+        self.rom_info = info;
+    }
+
+    /// Logs every executed instruction to `tracer`
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Lets a GDB remote protocol client control execution
+    pub fn set_gdb_server(&mut self, server: GdbServer) {
+        self.gdb = Some(server);
+    }
+
+    /// Counts executions per address and cycles per subroutine, shown in the debug menu
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    /// Records which addresses are executed or accessed as data, written as a report on exit
+    pub fn set_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn set_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+    }
+
+    /// Reloads the ROM whenever its file is modified
+    pub fn set_watch(&mut self, watch: bool) {
+        self.watch = watch;
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Saves a screenshot to `path` when emulation ends
+    pub fn set_exit_screenshot(&mut self, path: impl Into<PathBuf>) {
+        self.exit_screenshot = Some(path.into());
+    }
+
+    /// Records the framebuffer from the next timer tick until `stop_recording`, replacing any
+    /// recording in progress. GIFs are written to `path`, frames into `path` as a directory
+    pub fn start_recording(
+        &mut self,
+        path: impl AsRef<Path>,
+        format: RecordingFormat,
+    ) -> Result<(), Chip8Error> {
+        self.stop_recording()?;
+        let scale = self.settings.screenshots.scale;
+        self.recorder = Some(Recorder::create(self, path.as_ref(), format, scale)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), Chip8Error> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Width and height of the framebuffer in pixels
+    pub fn display_size(&self) -> (usize, usize) {
+        (self.display_width, self.display_height)
+    }
+
+    /// Whether the pixel at (`x`, `y`) is set, or `false` outside the framebuffer
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        x < self.display_width
+            && y < self.display_height
+            && self.framebuffer[y * self.display_width + x]
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Executes up to `cycles` instructions without drawing, reading input or playing sound,
+    /// stopping early if the program exits
+    pub fn run_headless(&mut self, cycles: usize) -> Result<(), Chip8Error> {
+        self.headless = true;
+        for _ in 0..cycles {
+            if !self.step()? {
+                break;
+            }
+        }
         Ok(())
     }
 
-    pub async fn run(&mut self, debug: u8) -> Result<(), Chip8Error> {
-        let mut timer: u8 = 0;
-        let mut halted = debug > 1;
-        let mut error = false;
-        let mut is_step = false;
+    pub async fn run(&mut self, debug: u8) -> Result<Exit, Chip8Error> {
+        // Upper bound on instructions executed per frame while running to a step target
+        const STEP_CYCLES_PER_FRAME: usize = 10_000;
+        // Host time spent emulating per frame when unthrottled, leaving room to draw at 60 fps
+        const UNTHROTTLED_FRAME_TIME: f64 = 0.012;
+        // Seconds a failed screenshot or recording is reported for
+        const NOTICE_TIME: f64 = 5.0;
+        let mut debugger = Debugger::new(debug > 1);
+        let mut renderer = Renderer::new();
+        let mut settings_open = false;
+        // Emulation stops while the settings window is first opened, like a pause menu
+        let mut paused = false;
+        if debug > 1 {
+            self.history = Some(History::new());
+        }
+        let mut modified = self.rom_modified();
+        // Chosen in the settings window or by hotkey, carried out at the start of a frame
+        let mut action = None;
+        // Fraction of an instruction carried over to the next frame below 1x
+        let mut budget = 0.0;
+        // Message shown over the display until the time given
+        let mut notice: Option<(String, f64)> = None;
+        // Closing the window ends the loop so that open files are flushed
+        prevent_quit();
         loop {
-            let op_byte1 = self.memory[self.program_counter as usize] as u16;
-            let op_byte2 = self.memory[self.program_counter as usize + 1] as u16;
-            let mut opcode: u16 = op_byte1 << 8 | op_byte2;
-            if !halted || is_step {
-                if is_step {
-                    is_step = false;
+            if is_quit_requested() {
+                return self.finish().map(|_| Exit::Quit);
+            }
+            if is_key_pressed(KeyCode::Escape) && !self.settings.is_capturing_key() {
+                settings_open = !settings_open;
+                paused = settings_open;
+            }
+            if self.watch {
+                let current = self.rom_modified();
+                if current != modified {
+                    action = Some(MenuAction::Reload);
+                }
+                modified = current;
+            }
+            if is_key_pressed(KeyCode::F2) {
+                let shift = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+                action = Some(if shift {
+                    MenuAction::Reload
+                } else {
+                    MenuAction::Reset
+                });
+            }
+            match action.take() {
+                Some(MenuAction::Reset) => {
+                    self.reset();
+                    debugger.reset(debug > 1);
                 }
-                timer += 1;
-                if timer % 5 == 0 {
-                    self.tick();
-                    timer = 0;
+                // A failed reload leaves the previous ROM running, as the file may be half written
+                Some(MenuAction::Reload) if self.reload().await.is_ok() => {
+                    debugger.reset(debug > 1);
                 }
-                for (idx, current_key) in KEY_MAP.into_iter().enumerate() {
-                    self.keys[idx] = is_key_down(current_key);
+                Some(MenuAction::Launcher) => return self.finish().map(|_| Exit::Launcher),
+                _ => {}
+            }
+            if is_key_pressed(KeyCode::F1) {
+                self.settings.keypad.visible = !self.settings.keypad.visible;
+            }
+            if is_key_pressed(KeyCode::F11) {
+                self.settings.fullscreen = !self.settings.fullscreen;
+            }
+            if is_key_pressed(KeyCode::F12) {
+                if let Err(error) = self.take_screenshot() {
+                    notice = Some((describe(&error), get_time() + NOTICE_TIME));
                 }
-                if self.program_counter == 0x200 && opcode == 0x1260 {
-                    // Init 64x64 hires mode
-                    self.display_width = 64;
-                    self.display_height = 64;
-                    opcode = 0x12C0;
-                    self.framebuffer = unsafe {
-                        UncheckedVec::new(vec![false; self.display_height * self.display_width])
-                    };
+            }
+            if is_key_pressed(KeyCode::F10) {
+                let result = if self.is_recording() {
+                    self.stop_recording()
+                } else {
+                    let format = self.settings.screenshots.recording_format;
+                    let extension = (format == RecordingFormat::Gif).then_some("gif");
+                    self.capture_path(extension)
+                        .map_err(|error| Chip8Error::Recording(error.into()))
+                        .and_then(|path| self.start_recording(path, format))
+                };
+                if let Err(error) = result {
+                    notice = Some((describe(&error), get_time() + NOTICE_TIME));
                 }
-                let op_1 = (opcode & 0xF000) >> 12;
-                let op_2 = (opcode & 0x0F00) >> 8;
-                let op_3 = (opcode & 0x00F0) >> 4;
-                let op_4 = opcode & 0x000F;
-                let x = op_2 as u8;
-                let y = op_3 as u8;
-                let nnn = opcode & 0x0FFF;
-                let kk = (opcode & 0x00FF) as u8;
-                let n = op_4 as u8;
-                self.next_instruction();
-                match (op_1, op_2, op_3, op_4) {
-                    (0, 0, 0, 0) => return Ok(()),
-                    (0, 0, 0xE, 0) | (0, 2, 3, 0) => self.cls(),
-                    (0, 0, 0xE, 0xE) => self.ret(),
-                    (0x1, _, _, _) => self.jp_addr(nnn),
-                    (0x2, _, _, _) => self.call_addr(nnn),
-                    (0x3, _, _, _) => self.se_vx_nn(x, kk),
-                    (0x4, _, _, _) => self.sne_vx_nn(x, kk),
-                    (0x5, _, _, _) => self.se_vx_vy(x, y),
-                    (0x6, _, _, _) => self.ld_vx_nn(x, kk),
-                    (0x7, _, _, _) => self.add_vx_nn(x, kk),
-                    (0x8, _, _, 0x0) => self.ld_vx_vy(x, y),
-                    (0x8, _, _, 0x1) => self.or_vx_vy(x, y),
-                    (0x8, _, _, 0x2) => self.and_vx_vy(x, y),
-                    (0x8, _, _, 0x3) => self.xor_vx_vy(x, y),
-                    (0x8, _, _, 0x4) => self.add_vx_vy(x, y),
-                    (0x8, _, _, 0x5) => self.sub_vx_vy(x, y),
-                    (0x8, _, _, 0x6) => self.shr_vx_vy(x),
-                    (0x8, _, _, 0x7) => self.subn_vx_vy(x, y),
-                    (0x8, _, _, 0xE) => self.shl_vx_vy(x),
-                    (0x9, _, _, _) => self.sne_vx_vy(x, y),
-                    (0xA, _, _, _) => self.ld_i_addr(nnn),
-                    (0xB, _, _, _) => self.jp_v0_addr(nnn),
-                    (0xC, _, _, _) => self.rnd_vx_nn(x, kk),
-                    (0xD, _, _, _) => self.drw_vx_vy_n(x, y, n),
-                    (0xE, _, 0x9, 0xE) => self.skp_vx(x),
-                    (0xE, _, 0xA, 0x1) => self.sknp_vx(x),
-                    (0xF, _, 0x0, 0x7) => self.ld_vx_dt(x),
-                    (0xF, _, 0x0, 0xA) => self.ld_vx_n(x),
-                    (0xF, _, 0x1, 0x5) => self.ld_dt_vx(x),
-                    (0xF, _, 0x1, 0x8) => self.ld_st_vx(x),
-                    (0xF, _, 0x1, 0xE) => self.add_i_vx(x),
-                    (0xF, _, 0x2, 0x9) => self.ld_f_vx(x),
-                    (0xF, _, 0x3, 0x3) => self.ld_b_vx(x),
-                    (0xF, _, 0x5, 0x5) => self.ld_i_vx(x),
-                    (0xF, _, 0x6, 0x5) => self.ld_vx_i(x),
-                    _ => {
-                        if debug == 0 {
-                            return Err(Chip8Error::IllegalInstruction(opcode));
+            }
+            if debug > 1 {
+                debugger.handle_shortcuts(self);
+            }
+            if let Some(mut gdb) = self.gdb.take() {
+                gdb.poll(self, &mut debugger);
+                self.gdb = Some(gdb);
+            }
+            self.rate = if is_key_down(KeyCode::Tab) && !self.settings.is_capturing_key() {
+                self.settings.fast_forward
+            } else {
+                self.settings.multiplier
+            };
+            let cycles = match self.rate {
+                _ if debugger.is_stepping() => STEP_CYCLES_PER_FRAME,
+                Multiplier::Times(factor) => {
+                    budget += self.settings.speed as f32 * factor;
+                    let cycles = budget as usize;
+                    budget -= cycles as f32;
+                    cycles
+                }
+                Multiplier::Unthrottled => usize::MAX,
+            };
+            let deadline = get_time() + UNTHROTTLED_FRAME_TIME;
+            for cycle in 0..cycles {
+                if paused || !debugger.is_running() {
+                    break;
+                }
+                if self.rate == Multiplier::Unthrottled && cycle % 256 == 0 && get_time() > deadline
+                {
+                    break;
+                }
+                match self.step() {
+                    Ok(true) => debugger.after_step(self),
+                    Ok(false) => {
+                        if let Some(gdb) = &mut self.gdb {
+                            gdb.exited();
+                        }
+                        return self.finish().map(|_| Exit::Finished);
+                    }
+                    Err(error) => {
+                        let debuggable = debug > 0 || self.gdb.is_some();
+                        let fatal = matches!(error, Chip8Error::Trace(_));
+                        if !debuggable || fatal {
+                            self.finish()?;
+                            return Err(error);
                         } else {
-                            halted = true;
-                            error = true;
+                            debugger.fault();
                         }
                     }
                 }
             }
-            let mut idx = 0;
-            let width_multiplier = screen_width() / self.display_width as f32;
-            let height_multiplier = screen_height() / self.display_height as f32;
-            for row in 0..self.display_height {
-                for col in 0..self.display_width {
-                    let cell = self.framebuffer[idx];
-                    let colour = if cell { GREEN } else { BLACK };
-                    draw_rectangle(
-                        col as f32 * width_multiplier,
-                        row as f32 * height_multiplier,
-                        width_multiplier,
-                        height_multiplier,
-                        colour,
-                    );
-                    idx += 1;
-                }
+            renderer.draw(self);
+            if self.rate != Multiplier::NORMAL {
+                draw_text(&self.rate.to_string(), 10.0, 26.0, 32.0, YELLOW);
             }
-            if debug > 0 {
-                egui_macroquad::ui(|egui_ctx| {
-                    egui::Window::new("Debug Menu").show(egui_ctx, |ui| {
-                        ui.label(format!("FPS: {}", get_fps()));
-                        if debug > 1 {
-                            ui.separator();
-                            let scroll_area = ScrollArea::vertical()
-                                .max_height(200.0)
-                                .auto_shrink([false; 2]);
-                            scroll_area.show(ui, |ui| {
-                                ui.vertical(|ui| {
-                                    let mut address = 0x200;
-                                    while address < 4096 {
-                                        let op_byte1 = self.memory[address] as u16;
-                                        let op_byte2 = self.memory[address + 1] as u16;
-                                        let opcode: u16 = op_byte1 << 8 | op_byte2;
-                                        if self.program_counter as usize == address {
-                                            let response = ui.colored_label(
-                                                Color32::YELLOW,
-                                                format!(
-                                                    "0x{address:04x} - {}",
-                                                    disassemble(opcode)
-                                                ),
-                                            );
-                                            response.scroll_to_me(Some(Align::Min));
-                                        } else {
-                                            ui.label(format!(
-                                                "0x{address:04x} - {}",
-                                                disassemble(opcode)
-                                            ));
-                                        }
-                                        address += 2;
-                                    }
-                                });
-                            });
-                            ui.separator();
-                            ui.horizontal(|ui| {
-                                ui.vertical(|ui| {
-                                    for idx in 0..16 {
-                                        let register = self.registers[idx];
-                                        ui.label(format!("V{idx}: {register}"));
-                                    }
-                                });
-                                ui.separator();
-                                ui.vertical(|ui| {
-                                    ui.label(format!("PC: {}", self.program_counter));
-                                    ui.label(format!("I: {}", self.index_register));
-                                });
-                                ui.separator();
-                                ui.vertical(|ui| {
-                                    if !error {
-                                        let text = if halted { "Start" } else { "Stop" };
-                                        if ui.button(text).clicked() {
-                                            halted = !halted;
-                                        }
-                                        if halted {
-                                            if ui.button("Step").clicked() {
-                                                is_step = true;
-                                            }
-                                        }
-                                    } else {
-                                        ui.label("A fatal error occurred!");
-                                    }
-                                })
-                            });
-                        }
-                    });
-                });
-                egui_macroquad::draw();
+            if let Some(error) = self.recording_error.take() {
+                notice = Some((describe(&error), get_time() + NOTICE_TIME));
+            }
+            notice = notice.filter(|(_, until)| get_time() < *until);
+            if let Some((message, _)) = &notice {
+                draw_text(message, 10.0, screen_height() - 12.0, 24.0, RED);
             }
+            egui_macroquad::ui(|egui_ctx| {
+                if debug > 0 {
+                    self.debug_menu(egui_ctx, &mut debugger, debug);
+                }
+                action = self.settings_window(egui_ctx, &mut settings_open, &mut paused);
+                self.keypad_window(egui_ctx);
+            });
+            // Closing the window resumes emulation
+            paused &= settings_open;
+            egui_macroquad::draw();
             next_frame().await;
         }
     }
 
+    /// Modification time of the ROM file, if it can be read
+    fn rom_modified(&self) -> Option<SystemTime> {
+        let location = self.rom_location.as_ref()?;
+        fs::metadata(&location.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    fn finish(&mut self) -> Result<(), Chip8Error> {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush().map_err(Chip8Error::Trace)?;
+        }
+        if let Some(coverage) = &self.coverage {
+            coverage.write_report(self)?;
+        }
+        self.stop_recording()?;
+        if let Some(path) = &self.exit_screenshot {
+            self.save_screenshot(path, self.settings.screenshots.scale)?;
+        }
+        Ok(())
+    }
+
+    /// Executes a single instruction, returning `false` once the program exits
+    fn step(&mut self) -> Result<bool, Chip8Error> {
+        let address = self.program_counter;
+        if address as usize + 1 >= MEMORY_SIZE {
+            return Err(Chip8Error::MemoryOutOfBounds(address as usize + 1));
+        }
+        let mut opcode = self.fetch(self.program_counter as usize);
+        self.record();
+        self.cycles += 1;
+        self.timer += 1;
+        // Timers tick once per emulated frame of `speed` instructions, so they stay at 60 Hz
+        // whatever the speed and only the multiplier changes their rate
+        if self.timer >= self.settings.speed {
+            self.tick();
+            self.apply_cheats();
+            self.timer = 0;
+            if let Some(mut recorder) = self.recorder.take() {
+                // A recording that can't be written stops, but the program keeps running
+                match recorder.frame(self) {
+                    Ok(()) => self.recorder = Some(recorder),
+                    Err(error) => self.recording_error = Some(error),
+                }
+            }
+        }
+        if !self.headless {
+            self.poll_keys();
+        }
+        if self.program_counter == 0x200 && opcode == 0x1260 {
+            // Init 64x64 hires mode
+            self.record_framebuffer();
+            self.display_width = 64;
+            self.display_height = 64;
+            opcode = 0x12C0;
+            self.framebuffer =
+                unsafe { UncheckedVec::new(vec![false; self.display_height * self.display_width]) };
+        }
+        let op_1 = (opcode & 0xF000) >> 12;
+        let op_2 = (opcode & 0x0F00) >> 8;
+        let op_3 = (opcode & 0x00F0) >> 4;
+        let op_4 = opcode & 0x000F;
+        let x = op_2 as u8;
+        let y = op_3 as u8;
+        let nnn = opcode & 0x0FFF;
+        let kk = (opcode & 0x00FF) as u8;
+        let n = op_4 as u8;
+        self.next_instruction();
+        match (op_1, op_2, op_3, op_4) {
+            (0, 0, 0, 0) => return Ok(false),
+            (0, 0, 0xE, 0) | (0, 2, 3, 0) => self.cls(),
+            (0, 0, 0xE, 0xE) => self.ret()?,
+            (0x1, _, _, _) => self.jp_addr(nnn),
+            (0x2, _, _, _) => self.call_addr(nnn)?,
+            (0x3, _, _, _) => self.se_vx_nn(x, kk),
+            (0x4, _, _, _) => self.sne_vx_nn(x, kk),
+            (0x5, _, _, 0x0) => self.se_vx_vy(x, y),
+            (0x6, _, _, _) => self.ld_vx_nn(x, kk),
+            (0x7, _, _, _) => self.add_vx_nn(x, kk),
+            (0x8, _, _, 0x0) => self.ld_vx_vy(x, y),
+            (0x8, _, _, 0x1) => self.or_vx_vy(x, y),
+            (0x8, _, _, 0x2) => self.and_vx_vy(x, y),
+            (0x8, _, _, 0x3) => self.xor_vx_vy(x, y),
+            (0x8, _, _, 0x4) => self.add_vx_vy(x, y),
+            (0x8, _, _, 0x5) => self.sub_vx_vy(x, y),
+            (0x8, _, _, 0x6) => self.shr_vx_vy(x, y),
+            (0x8, _, _, 0x7) => self.subn_vx_vy(x, y),
+            (0x8, _, _, 0xE) => self.shl_vx_vy(x, y),
+            (0x9, _, _, 0x0) => self.sne_vx_vy(x, y),
+            (0xA, _, _, _) => self.ld_i_addr(nnn),
+            (0xB, _, _, _) => self.jp_v0_addr(x, nnn),
+            (0xC, _, _, _) => self.rnd_vx_nn(x, kk),
+            (0xD, _, _, _) => self.drw_vx_vy_n(x, y, n)?,
+            (0xE, _, 0x9, 0xE) => self.skp_vx(x),
+            (0xE, _, 0xA, 0x1) => self.sknp_vx(x),
+            (0xF, _, 0x0, 0x7) => self.ld_vx_dt(x),
+            (0xF, _, 0x0, 0xA) => self.ld_vx_n(x),
+            (0xF, _, 0x1, 0x5) => self.ld_dt_vx(x),
+            (0xF, _, 0x1, 0x8) => self.ld_st_vx(x),
+            (0xF, _, 0x1, 0xE) => self.add_i_vx(x),
+            (0xF, _, 0x2, 0x9) => self.ld_f_vx(x),
+            (0xF, _, 0x3, 0x3) => self.ld_b_vx(x)?,
+            (0xF, _, 0x5, 0x5) => self.ld_i_vx(x)?,
+            (0xF, _, 0x6, 0x5) => self.ld_vx_i(x)?,
+            _ => return Err(Chip8Error::IllegalInstruction(opcode)),
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.instruction(address, opcode, self.cycles);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.executed(address as usize);
+        }
+        if let Some(mut tracer) = self.tracer.take() {
+            let result = tracer.log(self, address, opcode);
+            self.tracer = Some(tracer);
+            result.map_err(Chip8Error::Trace)?;
+        }
+        Ok(true)
+    }
+
+    /// Reads the opcode at `address`, wrapping around the end of memory so that the debugger
+    /// can show any address
+    fn fetch(&self, address: usize) -> u16 {
+        let op_byte1 = self.memory[address % MEMORY_SIZE] as u16;
+        let op_byte2 = self.memory[(address + 1) % MEMORY_SIZE] as u16;
+        op_byte1 << 8 | op_byte2
+    }
+
     fn clear_display(&mut self) {
-        self.framebuffer.clear();
-        clear_background(BLACK);
+        self.record_framebuffer();
+        for idx in 0..self.display_width * self.display_height {
+            self.framebuffer[idx] = false;
+        }
     }
 
-    fn enable_sound(&self) {
-        audio::play_sound_once(self.sound.clone());
+    fn enable_sound(&mut self) {
+        // Shortest gap between beeps away from normal speed
+        const BEEP_INTERVAL: f64 = 0.1;
+        let Some(sound) = self.sound else {
+            return;
+        };
+        if self.headless || self.settings.muted {
+            return;
+        }
+        let now = get_time();
+        if self.rate != Multiplier::NORMAL && now - self.last_beep < BEEP_INTERVAL {
+            return;
+        }
+        self.last_beep = now;
+        audio::play_sound(
+            sound,
+            PlaySoundParams {
+                looped: false,
+                volume: self.settings.volume,
+            },
+        );
     }
 
     fn draw_pixel(&mut self, x: usize, y: usize, value: u8) -> bool {
         let idx = y * self.display_width + x;
         let collision = self.framebuffer[idx];
+        if let Some(history) = &mut self.history {
+            history.pixel(idx, collision);
+        }
         self.framebuffer[idx] = (value == 1) ^ collision;
         collision
     }
 
+    fn read_memory(&mut self, address: usize) -> Result<u8, Chip8Error> {
+        if address >= MEMORY_SIZE {
+            return Err(Chip8Error::MemoryOutOfBounds(address));
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.data(address);
+        }
+        Ok(self.memory[address])
+    }
+
+    fn write_memory(&mut self, address: usize, value: u8) -> Result<(), Chip8Error> {
+        if address >= MEMORY_SIZE {
+            return Err(Chip8Error::MemoryOutOfBounds(address));
+        }
+        if let Some(history) = &mut self.history {
+            history.memory(address, self.memory[address]);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.data(address);
+        }
+        self.memory[address] = value;
+        Ok(())
+    }
+
     fn tick(&mut self) {
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
@@ -309,9 +763,13 @@ impl CPU {
     }
 
     // 00EE - Return from a subroutine
-    fn ret(&mut self) {
+    fn ret(&mut self) -> Result<(), Chip8Error> {
+        if self.stack_pointer == 0 {
+            return Err(Chip8Error::StackUnderflow);
+        }
         self.stack_pointer -= 1;
         self.program_counter = self.stack[self.stack_pointer as usize];
+        Ok(())
     }
 
     // 1nnn - Jump to location nnn
@@ -320,10 +778,14 @@ impl CPU {
     }
 
     // 2nnn - Call subroutine at nnn
-    fn call_addr(&mut self, addr: u16) {
+    fn call_addr(&mut self, addr: u16) -> Result<(), Chip8Error> {
+        if self.stack_pointer as usize >= STACK_SIZE {
+            return Err(Chip8Error::StackOverflow);
+        }
         self.stack[self.stack_pointer as usize] = self.program_counter;
         self.stack_pointer += 1;
         self.program_counter = addr;
+        Ok(())
     }
 
     // 3xnn - Skip next instruction if x = nn
@@ -354,7 +816,7 @@ impl CPU {
 
     // 7xnn - Set x = x + nn
     fn add_vx_nn(&mut self, x: u8, nn: u8) {
-        self.registers[x as usize] += nn;
+        self.registers[x as usize] = self.registers[x as usize].wrapping_add(nn);
     }
 
     // 8xy0 - Set x = y
@@ -365,52 +827,79 @@ impl CPU {
     // 8xy1 - Set x = x OR y
     fn or_vx_vy(&mut self, x: u8, y: u8) {
         self.registers[x as usize] |= self.registers[y as usize];
+        self.reset_flag();
     }
 
     // 8xy2 - Set x = x AND y
     fn and_vx_vy(&mut self, x: u8, y: u8) {
         self.registers[x as usize] &= self.registers[y as usize];
+        self.reset_flag();
     }
 
     // 8xy3 - Set x = x XOR y
     fn xor_vx_vy(&mut self, x: u8, y: u8) {
         self.registers[x as usize] ^= self.registers[y as usize];
+        self.reset_flag();
+    }
+
+    /// Clears VF after a logic instruction under the `vf_reset` quirk
+    fn reset_flag(&mut self) {
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
     }
 
     // 8xy4 - Set x = x + y, set VF = carry
     fn add_vx_vy(&mut self, x: u8, y: u8) {
         let (wrapped, is_overflow) =
             self.registers[x as usize].overflowing_add(self.registers[y as usize]);
-        self.registers[0xF] = if is_overflow { 1 } else { 0 };
-        self.registers[x as usize] = wrapped;
+        self.set_result_and_flag(x, wrapped, is_overflow as u8);
     }
 
     // 8xy5 - Set x = x - y, set VF = NOT borrow
     fn sub_vx_vy(&mut self, x: u8, y: u8) {
         let (wrapped, is_overflow) =
             self.registers[x as usize].overflowing_sub(self.registers[y as usize]);
-        self.registers[0xF] = if is_overflow { 0 } else { 1 };
-        self.registers[x as usize] = wrapped;
+        self.set_result_and_flag(x, wrapped, !is_overflow as u8);
     }
 
-    // 8xy6 - Set x = x SHR 1
-    fn shr_vx_vy(&mut self, x: u8) {
-        self.registers[0xF] = self.registers[x as usize] & 0x1;
-        self.registers[x as usize] >>= 1;
+    // 8xy6 - Set x = x SHR 1, or y SHR 1 under the `shift_vy` quirk
+    fn shr_vx_vy(&mut self, x: u8, y: u8) {
+        let value = self.shift_operand(x, y);
+        self.set_result_and_flag(x, value >> 1, value & 0x1);
     }
 
     // 8xy7 - Set x = y - x, set VF = NOT borrow
     fn subn_vx_vy(&mut self, x: u8, y: u8) {
         let (wrapped, is_overflow) =
             self.registers[y as usize].overflowing_sub(self.registers[x as usize]);
-        self.registers[0xF] = if is_overflow { 0 } else { 1 };
-        self.registers[x as usize] = wrapped;
+        self.set_result_and_flag(x, wrapped, !is_overflow as u8);
+    }
+
+    // 8xyE - Set x = x SHL 1, or y SHL 1 under the `shift_vy` quirk
+    fn shl_vx_vy(&mut self, x: u8, y: u8) {
+        let value = self.shift_operand(x, y);
+        self.set_result_and_flag(x, value << 1, value >> 7);
+    }
+
+    fn shift_operand(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_vy {
+            self.registers[y as usize]
+        } else {
+            self.registers[x as usize]
+        }
     }
 
-    // 8xyE - Set x = x SHL 1
-    fn shl_vx_vy(&mut self, x: u8) {
-        self.registers[0xF] = self.registers[x as usize] >> 7;
-        self.registers[x as usize] <<= 1;
+    /// Stores the result of an 8xy arithmetic instruction in Vx and its flag in VF, in the
+    /// order set by the `flag_last` quirk
+    fn set_result_and_flag(&mut self, x: u8, result: u8, flag: u8) {
+        if self.quirks.flag_last {
+            self.registers[x as usize] = result;
+            self.registers[0xF] = flag;
+        } else {
+            self.registers[0xF] = flag;
+            self.registers[x as usize] = result;
+        }
     }
 
     // 9xy0 - Skip next instruction if x != y
@@ -425,9 +914,14 @@ impl CPU {
         self.index_register = nnn;
     }
 
-    // Bnnn - Jump to location nnn + V0
-    fn jp_v0_addr(&mut self, nnn: u16) {
-        self.program_counter = nnn + self.registers[0] as u16;
+    // Bnnn - Jump to location nnn + V0, or xnn + Vx under the `jump_vx` quirk
+    fn jp_v0_addr(&mut self, x: u8, nnn: u16) {
+        let offset = if self.quirks.jump_vx {
+            self.registers[x as usize]
+        } else {
+            self.registers[0]
+        };
+        self.program_counter = nnn + offset as u16;
     }
 
     // Cxnn - Set Vx = random byte AND nn
@@ -437,39 +931,46 @@ impl CPU {
     }
 
     // Dxyn - Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision
-    fn drw_vx_vy_n(&mut self, x: u8, y: u8, n: u8) {
+    fn drw_vx_vy_n(&mut self, x: u8, y: u8, n: u8) -> Result<(), Chip8Error> {
         // If no pixels are erased, set VF to 0
         self.registers[0xF] = 0;
         // The interpreter reads n bytes from memory, starting at the address stored in I
         for i in 0..n {
-            let line = self.memory[self.index_register as usize + i as usize];
+            let line = self.read_memory(self.index_register as usize + i as usize)?;
             // Each byte is a line of eight pixels
             for position in 0..8 {
                 // Get the byte to set by position
                 let value = line >> (7 - position) & 0x01;
                 if value == 1 {
+                    // The sprite starts on screen, and either wraps or is clipped past the edges
+                    let x = self.registers[x as usize] as usize % self.display_width + position;
+                    let y = self.registers[y as usize] as usize % self.display_height + i as usize;
+                    if self.quirks.clip && (x >= self.display_width || y >= self.display_height) {
+                        continue;
+                    }
+                    let x = x % self.display_width;
+                    let y = y % self.display_height;
                     // If this causes any pixels to be erased, VF is set to 1
-                    let x = (self.registers[x as usize] as usize + position) % self.display_width; // wrap around width
-                    let y =
-                        (self.registers[y as usize] as usize + i as usize) % self.display_height; // wrap around height
                     if self.draw_pixel(x, y, value) {
                         self.registers[0xF] = 1;
                     }
                 }
             }
         }
+        Ok(())
     }
 
     // Ex9E - Skip next instruction if key with the value of Vx is pressed
     fn skp_vx(&mut self, x: u8) {
-        if self.keys[self.registers[x as usize] as usize] {
+        // Only the low nibble of Vx names a key
+        if self.keys[self.registers[x as usize] as usize & 0xF] {
             self.next_instruction();
         }
     }
 
     // ExA1 - Skip next instruction if key with the value of Vx is not pressed
     fn sknp_vx(&mut self, x: u8) {
-        if !self.keys[self.registers[x as usize] as usize] {
+        if !self.keys[self.registers[x as usize] as usize & 0xF] {
             self.next_instruction();
         }
     }
@@ -482,13 +983,17 @@ impl CPU {
     // Fx0A - Wait for a key press, store the value of the key in Vx
     fn ld_vx_n(&mut self, x: u8) {
         self.undo_instruction();
-        for idx in 0..16 {
-            let key = self.keys[idx as usize];
-            if key {
-                self.registers[x as usize] = idx;
-                self.next_instruction();
-                break;
-            }
+        // Keys already held when the wait started don't count
+        if self.awaited_key.is_none() {
+            self.awaited_key = (0..16).find(|&idx| self.pressed_keys[idx as usize]);
+        }
+        let Some(key) = self.awaited_key else {
+            return;
+        };
+        if self.quirks.key_wait == KeyWait::Press || !self.keys[key as usize] {
+            self.registers[x as usize] = key;
+            self.awaited_key = None;
+            self.next_instruction();
         }
     }
 
@@ -507,7 +1012,9 @@ impl CPU {
 
     // Fx1E - Set I = I + Vx
     fn add_i_vx(&mut self, x: u8) {
-        self.index_register += self.registers[x as usize] as u16;
+        self.index_register = self
+            .index_register
+            .wrapping_add(self.registers[x as usize] as u16);
     }
 
     // Fx29 - Set I = location of sprite for digit Vx
@@ -518,27 +1025,55 @@ impl CPU {
     // Fx33 - Store BCD representation of Vx in memory locations I, I+1, and I+2
     // BCD means binary-coded decimal
     // If VX is 0xef, or 239, we want 2, 3, and 9 in I, I+1, and I+2
-    fn ld_b_vx(&mut self, x: u8) {
-        self.memory[self.index_register as usize] = self.registers[x as usize] / 100;
-        self.memory[self.index_register as usize + 1] = (self.registers[x as usize] / 10) % 10;
-        self.memory[self.index_register as usize + 2] = self.registers[x as usize] % 10;
+    fn ld_b_vx(&mut self, x: u8) -> Result<(), Chip8Error> {
+        let address = self.index_register as usize;
+        self.write_memory(address, self.registers[x as usize] / 100)?;
+        self.write_memory(address + 1, (self.registers[x as usize] / 10) % 10)?;
+        self.write_memory(address + 2, self.registers[x as usize] % 10)
     }
 
     // Fx55 - Store registers V0 through Vx in memory starting at location I
-    fn ld_i_vx(&mut self, x: u8) {
+    fn ld_i_vx(&mut self, x: u8) -> Result<(), Chip8Error> {
         for idx in 0..=x {
-            self.memory[self.index_register as usize + idx as usize] = self.registers[idx as usize];
+            self.write_memory(
+                self.index_register as usize + idx as usize,
+                self.registers[idx as usize],
+            )?;
         }
+        self.advance_index(x);
+        Ok(())
     }
 
     // Fx65 - Read registers V0 through Vx from memory starting at location I
-    fn ld_vx_i(&mut self, x: u8) {
+    fn ld_vx_i(&mut self, x: u8) -> Result<(), Chip8Error> {
         for idx in 0..=x {
-            self.registers[idx as usize] = self.memory[self.index_register as usize + idx as usize];
+            self.registers[idx as usize] =
+                self.read_memory(self.index_register as usize + idx as usize)?;
+        }
+        self.advance_index(x);
+        Ok(())
+    }
+
+    /// Moves I past the registers transferred by Fx55 or Fx65 under the `increment_index` quirk
+    fn advance_index(&mut self, x: u8) {
+        if self.quirks.increment_index {
+            self.index_register = self.index_register.wrapping_add(x as u16 + 1);
         }
     }
 }
 
+/// The message of `error` followed by those of its causes
+fn describe(error: &Chip8Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
 fn disassemble(opcode: u16) -> Cow<'static, str> {
     let op_1 = (opcode & 0xF000) >> 12;
     let op_2 = (opcode & 0x0F00) >> 8;
@@ -556,7 +1091,7 @@ fn disassemble(opcode: u16) -> Cow<'static, str> {
         (0x2, _, _, _) => format!("CALL 0x{nnn:04x}").into(),
         (0x3, _, _, _) => format!("SE V{n} {kk}").into(),
         (0x4, _, _, _) => format!("SNE V{n} {kk}").into(),
-        (0x5, _, _, _) => format!("SE V{x} V{y}").into(),
+        (0x5, _, _, 0x0) => format!("SE V{x} V{y}").into(),
         (0x6, _, _, _) => format!("LD V{x} {kk}").into(),
         (0x7, _, _, _) => format!("ADD V{x} {kk}").into(),
         (0x8, _, _, 0x0) => format!("LD V{x} V{y}").into(),
@@ -568,7 +1103,7 @@ fn disassemble(opcode: u16) -> Cow<'static, str> {
         (0x8, _, _, 0x6) => format!("SHR V{x}").into(),
         (0x8, _, _, 0x7) => format!("SUBN V{x} V{y}").into(),
         (0x8, _, _, 0xE) => format!("SHL V{x}").into(),
-        (0x9, _, _, _) => format!("SNE V{x} V{y}").into(),
+        (0x9, _, _, 0x0) => format!("SNE V{x} V{y}").into(),
         (0xA, _, _, _) => format!("LDI {nnn}").into(),
         (0xB, _, _, _) => format!("JP V0 + {nnn}").into(),
         (0xC, _, _, _) => format!("RND {kk}").into(),
@@ -588,6 +1123,17 @@ fn disassemble(opcode: u16) -> Cow<'static, str> {
     }
 }
 
+/// Why `CPU::run` returned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// The program executed `0000`
+    Finished,
+    /// The window was closed
+    Quit,
+    /// The player asked to choose another ROM
+    Launcher,
+}
+
 #[derive(Error, Debug)]
 pub enum Chip8Error {
     #[error("error reading file")]
@@ -595,4 +1141,106 @@ pub enum Chip8Error {
 
     #[error("illegal instruction: {0:04x}")]
     IllegalInstruction(u16),
+
+    #[error("stack overflow calling a subroutine")]
+    StackOverflow,
+
+    #[error("return from a subroutine with an empty stack")]
+    StackUnderflow,
+
+    #[error("memory access out of bounds at 0x{0:04x}")]
+    MemoryOutOfBounds(usize),
+
+    #[error("error writing trace")]
+    Trace(#[source] std::io::Error),
+
+    #[error("error writing coverage report")]
+    Coverage(#[source] std::io::Error),
+
+    #[error("error reading config file")]
+    ConfigIo(#[source] std::io::Error),
+
+    #[error("invalid config file")]
+    ConfigParse(#[from] toml::de::Error),
+
+    #[error("error starting GDB server")]
+    Gdb(#[source] std::io::Error),
+
+    #[error("error saving screenshot")]
+    Screenshot(#[source] image::ImageError),
+
+    #[error("error writing recording")]
+    Recording(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("error reading zip archive")]
+    Archive(#[from] zip::result::ZipError),
+
+    #[error("no ROM named {0} in the zip archive")]
+    ArchiveEntry(String),
+
+    #[error("error applying patch {name}: {message}")]
+    Patch { name: String, message: String },
+
+    #[error("error reading cheats file")]
+    CheatsIo(#[source] std::io::Error),
+
+    #[error("invalid cheats file")]
+    CheatsParse(#[source] toml::de::Error),
+
+    #[error("invalid Octo cartridge: {0}")]
+    Cartridge(String),
+
+    #[error("error assembling Octo program on line {line}: {message}")]
+    Assembly { line: usize, message: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `program` from 0x200 until it exits or faults
+    fn run(program: &[u8]) -> Result<CPU, Chip8Error> {
+        let mut cpu = CPU::with_sound(None);
+        for (idx, &byte) in program.iter().enumerate() {
+            cpu.memory[0x200 + idx] = byte;
+        }
+        cpu.run_headless(1000)?;
+        Ok(cpu)
+    }
+
+    #[test]
+    fn stack_faults() {
+        assert!(matches!(
+            run(&[0x00, 0xEE]),
+            Err(Chip8Error::StackUnderflow)
+        ));
+        // Calls itself until the stack is full
+        assert!(matches!(run(&[0x22, 0x00]), Err(Chip8Error::StackOverflow)));
+    }
+
+    #[test]
+    fn memory_faults() {
+        // Fx65 with I at the last byte reads one past the end
+        assert!(matches!(
+            run(&[0xAF, 0xFF, 0xF1, 0x65]),
+            Err(Chip8Error::MemoryOutOfBounds(0x1000))
+        ));
+        // Dxyn reads its sprite from I onwards
+        assert!(matches!(
+            run(&[0xAF, 0xFE, 0xD0, 0x03]),
+            Err(Chip8Error::MemoryOutOfBounds(0x1000))
+        ));
+        // Bnnn can jump past the end of memory
+        assert!(matches!(
+            run(&[0x60, 0xFF, 0xBF, 0xFF]),
+            Err(Chip8Error::MemoryOutOfBounds(0x10FF))
+        ));
+    }
+
+    #[test]
+    fn keys_use_the_low_nibble() {
+        let cpu = run(&[0x60, 0xFF, 0xE0, 0x9E, 0x70, 0x02, 0x00, 0x00]).unwrap();
+        // Key F is not pressed, so 7002 runs and wraps V0 around
+        assert_eq!(cpu.registers[0], 0x01);
+    }
 }
@@ -1,24 +1,330 @@
 use anyhow::Result;
-use chippy::CPU;
+use chippy::{
+    find_patches, is_archive, Cheats, Config, Coverage, Exit, GdbServer, HexColour, KeyBinding,
+    KeyWait, Launcher, Multiplier, PersistenceMode, RecordingFormat, RomLocation, ScalingMode,
+    Settings, Theme, TraceFormat, Tracer, CPU,
+};
 use clap::Parser;
-use std::path::PathBuf;
+use std::{net::SocketAddr, ops::RangeInclusive, path::PathBuf};
 
 #[macroquad::main("Chippy")]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    // Archives without a chosen entry are browsed in the launcher
+    let (mut launcher, mut rom) = match &args.rom {
+        Some(path) if is_archive(path) && args.entry.is_none() => (Launcher::new(path), None),
+        Some(path) => (
+            Launcher::new(&args.rom_dir),
+            Some(RomLocation {
+                path: path.clone(),
+                entry: args.entry.clone(),
+            }),
+        ),
+        None => (Launcher::new(&args.rom_dir), None),
+    };
+    // Programs chosen in the launcher return to it when they exit
+    let mut from_launcher = rom.is_none();
+    let mut previous: Option<Settings> = None;
+    loop {
+        let location = match rom.take() {
+            Some(location) => location,
+            None => match launcher.choose().await {
+                Some(location) => {
+                    from_launcher = true;
+                    location
+                }
+                None => return Ok(()),
+            },
+        };
+        let result = match start(&args, &location, previous.take()).await {
+            Ok(mut cpu) => {
+                let result = cpu.run(args.debug).await;
+                previous = Some(cpu.settings().clone());
+                result.map_err(anyhow::Error::from)
+            }
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(Exit::Quit) => return Ok(()),
+            Ok(Exit::Finished) if !from_launcher => return Ok(()),
+            Ok(Exit::Finished | Exit::Launcher) => {}
+            Err(error) if from_launcher => launcher.show_error(&location, &error),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Loads the ROM at `location` and applies the config file and command line options to it,
+/// keeping the display settings of the previously running ROM if there was one
+async fn start(args: &Args, location: &RomLocation, previous: Option<Settings>) -> Result<CPU> {
+    let mut config = Config::load(args.config.as_deref())?;
     let mut cpu = CPU::new().await;
-    cpu.load(args.rom.to_str().unwrap()).await?;
-    cpu.run(args.debug).await?;
-    Ok(())
+    cpu.load_location(location).await?;
+    if let Some(directory) = args.patch_dir.clone().or_else(|| config.patch_directory()) {
+        for path in find_patches(&directory, &cpu.rom_info().hash) {
+            cpu.add_patch(&path).await?;
+        }
+    }
+    for path in &args.patch {
+        cpu.add_patch(path).await?;
+    }
+    if let Some(directory) = config.cheat_directory() {
+        cpu.set_cheats(Cheats::load(&directory, &cpu.rom_info().hash)?);
+    }
+    let info = config.select_rom(cpu.rom_name(), cpu.rom_info(), cpu.embedded_options())?;
+    cpu.set_rom_info(info);
+    for binding in &args.key {
+        config.keys.insert(binding.key, binding.host_keys.clone());
+    }
+    if args.palette.is_some() {
+        config.palette = args.palette;
+    }
+    if args.foreground.is_some() {
+        config.foreground = args.foreground;
+    }
+    if args.background.is_some() {
+        config.background = args.background;
+    }
+    if let Some(mode) = args.persistence {
+        config.persistence.mode = mode;
+    }
+    if let Some(frames) = args.blend_frames {
+        config.persistence.frames = frames;
+    }
+    if let Some(decay) = args.decay {
+        config.persistence.decay = decay;
+    }
+    if let Some(mode) = args.scaling {
+        config.scaling.mode = mode;
+    }
+    if let Some(border) = args.border {
+        config.scaling.border = border.0;
+    }
+    config.scaling.grid |= args.grid;
+    config.fullscreen |= args.fullscreen;
+    config.keypad |= args.keypad;
+    if args.speed.is_some() {
+        config.speed = args.speed;
+    }
+    if args.multiplier.is_some() {
+        config.multiplier = args.multiplier;
+    }
+    if args.fast_forward.is_some() {
+        config.fast_forward = args.fast_forward;
+    }
+    if args.volume.is_some() {
+        config.volume = args.volume;
+    }
+    config.mute |= args.mute;
+    if let Some(key_wait) = args.key_wait {
+        config.quirks.key_wait = key_wait;
+    }
+    if let Some(directory) = &args.screenshot_dir {
+        config.screenshots.directory = directory.clone();
+    }
+    if let Some(scale) = args.screenshot_scale {
+        config.screenshots.scale = scale;
+    }
+    let mut settings = Settings::from(&config);
+    if let Some(previous) = previous {
+        settings.keep_display(&previous);
+    }
+    cpu.set_settings(settings);
+    cpu.set_quirks(config.quirks);
+    cpu.set_watch(args.watch);
+    if let Some(path) = &args.trace {
+        cpu.set_tracer(Tracer::create(
+            path,
+            args.trace_format,
+            args.trace_range.clone(),
+        )?);
+    }
+    if let Some(path) = &args.screenshot {
+        cpu.set_exit_screenshot(path);
+    }
+    if let Some(path) = &args.record {
+        let format = args
+            .record_format
+            .unwrap_or(config.screenshots.recording_format);
+        cpu.start_recording(path, format)?;
+    }
+    if let Some(path) = &args.coverage {
+        cpu.set_coverage(Coverage::new(path));
+    }
+    if args.profile {
+        cpu.enable_profiler();
+    }
+    if let Some(address) = args.gdb {
+        cpu.set_gdb_server(GdbServer::bind(address)?);
+    }
+    Ok(cpu)
 }
 
 #[derive(Debug, Parser)]
 #[clap(version, about)]
 struct Args {
-    /// Path to the ROM binary
-    rom: PathBuf,
+    /// Path to the ROM binary, Octo cartridge or zip archive of ROMs, or choose one from
+    /// --rom-dir if omitted
+    rom: Option<PathBuf>,
+
+    /// ROM to run from a zip archive, or choose one in the launcher if omitted
+    #[clap(long, value_name = "NAME", requires = "rom")]
+    entry: Option<String>,
+
+    /// Apply an IPS or BPS patch to the ROM when it is loaded (repeatable)
+    #[clap(long, value_name = "FILE")]
+    patch: Vec<PathBuf>,
+
+    /// Directory of patches named after the SHA-1 hash of the ROM they apply to, such as
+    /// <hash>.ips [default: chippy/patches in the user config directory]
+    #[clap(long, value_name = "DIR")]
+    patch_dir: Option<PathBuf>,
+
+    /// Reload the ROM whenever the file changes
+    #[clap(short, long)]
+    watch: bool,
+
+    /// Directory of ROMs listed in the launcher
+    #[clap(long, value_name = "DIR", default_value = "roms")]
+    rom_dir: PathBuf,
 
     /// Enable debug menu (spamming this increases verbosity)
     #[clap(short, long, action = clap::ArgAction::Count)]
     debug: u8,
+
+    /// Path to the config file [default: chippy/config.toml in the user config directory]
+    #[clap(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Colour palette
+    #[clap(short, long, value_enum)]
+    palette: Option<Theme>,
+
+    /// Foreground colour as #rrggbb, overriding the palette
+    #[clap(long, value_name = "COLOUR")]
+    foreground: Option<HexColour>,
+
+    /// Background colour as #rrggbb, overriding the palette
+    #[clap(long, value_name = "COLOUR")]
+    background: Option<HexColour>,
+
+    /// Reduce flicker by blending recent frames or letting pixels fade out
+    #[clap(long, value_enum)]
+    persistence: Option<PersistenceMode>,
+
+    /// Number of frames averaged with --persistence blend
+    #[clap(long, value_name = "FRAMES")]
+    blend_frames: Option<usize>,
+
+    /// Brightness kept per frame with --persistence decay, between 0 and 1
+    #[clap(long, value_name = "FACTOR")]
+    decay: Option<f32>,
+
+    /// How the display is fitted into the window
+    #[clap(long, value_enum)]
+    scaling: Option<ScalingMode>,
+
+    /// Letterbox colour as #rrggbb
+    #[clap(long, value_name = "COLOUR")]
+    border: Option<HexColour>,
+
+    /// Draw a grid between pixels
+    #[clap(long)]
+    grid: bool,
+
+    /// Start in fullscreen (toggle with F11)
+    #[clap(long)]
+    fullscreen: bool,
+
+    /// Directory for screenshots taken with F12 [default: current directory]
+    #[clap(long, value_name = "DIR")]
+    screenshot_dir: Option<PathBuf>,
+
+    /// Size of each pixel in screenshots
+    #[clap(long, value_name = "SCALE", value_parser = clap::value_parser!(u32).range(1..=64))]
+    screenshot_scale: Option<u32>,
+
+    /// Save a PNG screenshot to a file when emulation ends
+    #[clap(long, value_name = "FILE")]
+    screenshot: Option<PathBuf>,
+
+    /// Record gameplay from the start to a GIF file or a directory of frames (toggle with F10)
+    #[clap(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+
+    /// Format of recordings [default: gif]
+    #[clap(long, value_enum)]
+    record_format: Option<RecordingFormat>,
+
+    /// Bind host keys to a keypad key, such as 5=W,Up (repeatable)
+    #[clap(short, long, value_name = "KEY=HOSTKEYS")]
+    key: Vec<KeyBinding>,
+
+    /// Show a clickable keypad (toggle with F1)
+    #[clap(long)]
+    keypad: bool,
+
+    /// Instructions executed per frame [default: 5]
+    #[clap(short, long)]
+    speed: Option<usize>,
+
+    /// Emulation rate relative to normal, from 0.25 to 16 or "unthrottled" [default: 1]
+    #[clap(short, long, value_name = "MULTIPLIER")]
+    multiplier: Option<Multiplier>,
+
+    /// Rate while Tab is held [default: 8]
+    #[clap(long, value_name = "MULTIPLIER")]
+    fast_forward: Option<Multiplier>,
+
+    /// Beeper volume between 0 and 1
+    #[clap(long, value_name = "VOLUME")]
+    volume: Option<f32>,
+
+    /// Start with sound muted
+    #[clap(long)]
+    mute: bool,
+
+    /// When Fx0A stops waiting for a key [default: release]
+    #[clap(long, value_enum)]
+    key_wait: Option<KeyWait>,
+
+    /// Log every executed instruction to a file
+    #[clap(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Format of the trace file
+    #[clap(long, value_enum, default_value_t = TraceFormat::Text)]
+    trace_format: TraceFormat,
+
+    /// Only trace instructions within an address range such as 0x200-0x2ff (repeatable)
+    #[clap(long, value_name = "START-END", value_parser = parse_range)]
+    trace_range: Vec<RangeInclusive<u16>>,
+
+    /// Write a code coverage report on exit (HTML if the file ends in .html, text otherwise)
+    #[clap(long, value_name = "FILE")]
+    coverage: Option<PathBuf>,
+
+    /// Profile execution counts per address and cycles per subroutine (shown in the debug menu)
+    #[clap(long)]
+    profile: bool,
+
+    /// Serve the GDB remote protocol on an address such as 127.0.0.1:1234
+    #[clap(long, value_name = "ADDRESS")]
+    gdb: Option<SocketAddr>,
+}
+
+fn parse_range(range: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = range
+        .split_once('-')
+        .ok_or_else(|| "expected a range such as 0x200-0x2ff".to_string())?;
+    Ok(parse_address(start)?..=parse_address(end)?)
+}
+
+fn parse_address(address: &str) -> Result<u16, String> {
+    let address = address.trim();
+    let parsed = match address.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => address.parse(),
+    };
+    parsed.map_err(|_| format!("invalid address: {address}"))
 }
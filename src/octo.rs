@@ -0,0 +1,1161 @@
+use crate::Chip8Error;
+use std::collections::{HashMap, VecDeque};
+
+const PROGRAM_START: usize = 0x200;
+/// Tokens that macros and string modes may expand to in total, so that recursive definitions
+/// fail rather than expanding forever
+const EXPANSION_LIMIT: usize = 1 << 18;
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    /// Whether the token was written as a quoted string
+    string: bool,
+    line: usize,
+}
+
+/// Splits Octo source into whitespace separated tokens, dropping `#` comments
+fn tokenize(source: &str) -> Result<VecDeque<Token>, Chip8Error> {
+    let mut tokens = VecDeque::new();
+    for (idx, line) in source.lines().enumerate() {
+        let line_number = idx + 1;
+        let mut chars = line.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c == '#' {
+                break;
+            } else if c == '"' {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some('r') => text.push('\r'),
+                            Some('0') => text.push('\0'),
+                            Some(c) => text.push(c),
+                            None => return Err(assembly_error(line_number, "unterminated string")),
+                        },
+                        Some(c) => text.push(c),
+                        None => return Err(assembly_error(line_number, "unterminated string")),
+                    }
+                }
+                tokens.push_back(Token {
+                    text,
+                    string: true,
+                    line: line_number,
+                });
+            } else {
+                let mut text = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+                tokens.push_back(Token {
+                    text,
+                    string: false,
+                    line: line_number,
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn assembly_error(line: usize, message: impl Into<String>) -> Chip8Error {
+    Chip8Error::Assembly {
+        line,
+        message: message.into(),
+    }
+}
+
+/// Parses a decimal, `0x` hexadecimal or `0b` binary literal, optionally negative
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    if digit.len() == 1 {
+        u8::from_str_radix(digit, 16).ok()
+    } else {
+        None
+    }
+}
+
+/// How an unresolved reference is patched once its label is defined
+#[derive(Clone, Copy)]
+enum Fixup {
+    /// Low 12 bits of the instruction at the address
+    Address,
+    /// Both bytes at the address
+    Long,
+    /// Low nybble of the byte after the address and the byte 3 after, as written by `:unpack`
+    Unpack,
+    /// Byte after the address and the byte 3 after, as written by `:unpack long`
+    UnpackLong,
+}
+
+/// Comparison in an `if` or `while` condition
+#[derive(Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Key,
+    NotKey,
+}
+
+impl Comparison {
+    fn parse(text: &str) -> Option<Self> {
+        Some(match text {
+            "==" => Self::Equal,
+            "!=" => Self::NotEqual,
+            "<" => Self::Less,
+            ">" => Self::Greater,
+            "<=" => Self::LessEqual,
+            ">=" => Self::GreaterEqual,
+            "key" => Self::Key,
+            "-key" => Self::NotKey,
+            _ => return None,
+        })
+    }
+
+    fn negate(self) -> Self {
+        match self {
+            Self::Equal => Self::NotEqual,
+            Self::NotEqual => Self::Equal,
+            Self::Less => Self::GreaterEqual,
+            Self::Greater => Self::LessEqual,
+            Self::LessEqual => Self::Greater,
+            Self::GreaterEqual => Self::Less,
+            Self::Key => Self::NotKey,
+            Self::NotKey => Self::Key,
+        }
+    }
+}
+
+/// Register, comparison and operand of an `if` or `while`
+type Condition = (u16, Comparison, Option<Operand>);
+
+enum Operand {
+    Register(u8),
+    Value(u8),
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+/// Compiles Octo assembly language to CHIP-8 bytecode
+struct Assembler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    /// Memory from 0x200 upwards
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    /// Bodies expanded for each character of a string, keyed by mode name and character
+    string_modes: HashMap<String, HashMap<char, (usize, Vec<Token>)>>,
+    /// References to labels that have not been defined yet
+    unresolved: HashMap<String, Vec<(usize, Fixup, usize)>>,
+    /// Jumps waiting to be pointed at the `else` or `end` of an `if ... begin` block
+    branches: Vec<usize>,
+    /// Start of each open `loop` with the `while` jumps out of it
+    loops: Vec<(usize, Vec<usize>)>,
+    /// Tokens produced by macro and string mode expansions so far
+    expanded: usize,
+}
+
+/// Assembles Octo source into a program loaded at 0x200
+pub(crate) fn assemble(source: &str) -> Result<Vec<u8>, Chip8Error> {
+    let mut assembler = Assembler {
+        tokens: tokenize(source)?,
+        line: 1,
+        rom: Vec::new(),
+        here: PROGRAM_START,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::from([("unpack-hi".to_string(), 0), ("unpack-lo".to_string(), 1)]),
+        macros: HashMap::new(),
+        string_modes: HashMap::new(),
+        unresolved: HashMap::new(),
+        branches: Vec::new(),
+        loops: Vec::new(),
+        expanded: 0,
+    };
+    // Execution starts with a jump to `main`
+    assembler.reference("main".to_string(), Fixup::Address);
+    assembler.instruction(0x1000);
+    while let Some(token) = assembler.tokens.pop_front() {
+        assembler.line = token.line;
+        assembler.statement(token)?;
+    }
+    assembler.finish()
+}
+
+impl Assembler {
+    fn error(&self, message: impl Into<String>) -> Chip8Error {
+        assembly_error(self.line, message)
+    }
+
+    fn next(&mut self) -> Result<Token, Chip8Error> {
+        let token = self
+            .tokens
+            .pop_front()
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        self.line = token.line;
+        Ok(token)
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens
+            .front()
+            .is_some_and(|token| !token.string && token.text == text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), Chip8Error> {
+        let token = self.next()?;
+        if token.text == text && !token.string {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected '{text}', found '{}'", token.text)))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, Chip8Error> {
+        let token = self.next()?;
+        if token.string || parse_number(&token.text).is_some() || self.is_register(&token.text) {
+            Err(self.error(format!("invalid name '{}'", token.text)))
+        } else {
+            Ok(token.text)
+        }
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        parse_register(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    fn register(&mut self) -> Result<u8, Chip8Error> {
+        let token = self.next()?;
+        parse_register(&token.text)
+            .or_else(|| self.aliases.get(&token.text).copied())
+            .ok_or_else(|| self.error(format!("expected a register, found '{}'", token.text)))
+    }
+
+    fn write(&mut self, byte: u8) {
+        let idx = self.here - PROGRAM_START;
+        if idx >= self.rom.len() {
+            self.rom.resize(idx + 1, 0);
+        }
+        self.rom[idx] = byte;
+        self.here += 1;
+    }
+
+    fn instruction(&mut self, opcode: u16) {
+        let [hi, lo] = opcode.to_be_bytes();
+        self.write(hi);
+        self.write(lo);
+    }
+
+    fn defined(&self, name: &str) -> Option<f64> {
+        match name {
+            "HERE" => Some(self.here as f64),
+            _ => self
+                .constants
+                .get(name)
+                .copied()
+                .or_else(|| self.labels.get(name).map(|&address| address as f64)),
+        }
+    }
+
+    /// Value of a number, constant or label
+    fn value(&mut self) -> Result<f64, Chip8Error> {
+        let token = self.next()?;
+        if token.text == "{" && !token.string {
+            self.tokens.push_front(token);
+            let expression = self.block()?;
+            return self.evaluate(&expression);
+        }
+        parse_number(&token.text)
+            .or_else(|| self.defined(&token.text))
+            .ok_or_else(|| self.error(format!("undefined name '{}'", token.text)))
+    }
+
+    fn byte(&mut self) -> Result<u8, Chip8Error> {
+        let value = self.value()?;
+        self.to_byte(value)
+    }
+
+    fn to_byte(&self, value: f64) -> Result<u8, Chip8Error> {
+        if (-128.0..256.0).contains(&value) {
+            Ok(value as i64 as u8)
+        } else {
+            Err(self.error(format!("value {value} does not fit in a byte")))
+        }
+    }
+
+    fn nybble(&mut self) -> Result<u16, Chip8Error> {
+        let value = self.value()?;
+        if (0.0..16.0).contains(&value) {
+            Ok(value as u16)
+        } else {
+            Err(self.error(format!("value {value} does not fit in a nybble")))
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, Chip8Error> {
+        match self.tokens.front() {
+            Some(token) if !token.string && self.is_register(&token.text) => {
+                self.register().map(Operand::Register)
+            }
+            _ => self.byte().map(Operand::Value),
+        }
+    }
+
+    /// Notes that the instruction being written at `here` refers to `name`
+    fn reference(&mut self, name: String, fixup: Fixup) {
+        let line = self.line;
+        self.unresolved
+            .entry(name)
+            .or_default()
+            .push((self.here, fixup, line));
+    }
+
+    /// Address operand, which may refer to a label defined later
+    fn address(&mut self, fixup: Fixup) -> Result<u16, Chip8Error> {
+        let token = self.next()?;
+        let value = if token.text == "{" && !token.string {
+            self.tokens.push_front(token);
+            let expression = self.block()?;
+            self.evaluate(&expression)?
+        } else if let Some(value) = parse_number(&token.text).or_else(|| self.defined(&token.text))
+        {
+            value
+        } else if token.string || self.is_register(&token.text) {
+            return Err(self.error(format!("expected an address, found '{}'", token.text)));
+        } else {
+            self.reference(token.text, fixup);
+            0.0
+        };
+        let limit = match fixup {
+            Fixup::Address => 0x1000,
+            _ => 0x10000,
+        };
+        if (0.0..limit as f64).contains(&value) {
+            Ok(value as u16)
+        } else {
+            Err(self.error(format!("address {value} is out of range")))
+        }
+    }
+
+    fn patch(&mut self, at: usize, fixup: Fixup, address: u16) {
+        let idx = at - PROGRAM_START;
+        let [hi, lo] = address.to_be_bytes();
+        match fixup {
+            Fixup::Address => {
+                self.rom[idx] = (self.rom[idx] & 0xF0) | (hi & 0x0F);
+                self.rom[idx + 1] = lo;
+            }
+            Fixup::Long => {
+                self.rom[idx] = hi;
+                self.rom[idx + 1] = lo;
+            }
+            Fixup::Unpack => {
+                self.rom[idx + 1] = (self.rom[idx + 1] & 0xF0) | (hi & 0x0F);
+                self.rom[idx + 3] = lo;
+            }
+            Fixup::UnpackLong => {
+                self.rom[idx + 1] = hi;
+                self.rom[idx + 3] = lo;
+            }
+        }
+    }
+
+    fn define_label(&mut self, name: String, address: usize) -> Result<(), Chip8Error> {
+        if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+            return Err(self.error(format!("'{name}' is already defined")));
+        }
+        let address = address as u16;
+        for (at, fixup, _) in self.unresolved.remove(&name).unwrap_or_default() {
+            self.patch(at, fixup, address);
+        }
+        self.labels.insert(name, address);
+        Ok(())
+    }
+
+    /// Tokens between a pair of braces, which may be nested
+    fn block(&mut self) -> Result<Vec<Token>, Chip8Error> {
+        self.expect("{")?;
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let token = self.next()?;
+            if !token.string {
+                match token.text.as_str() {
+                    "{" => depth += 1,
+                    "}" => depth -= 1,
+                    _ => {}
+                }
+            }
+            if depth == 0 {
+                return Ok(body);
+            }
+            body.push(token);
+        }
+    }
+
+    /// Evaluates a `:calc` expression, in which operators are applied right to left
+    fn evaluate(&self, tokens: &[Token]) -> Result<f64, Chip8Error> {
+        let mut position = 0;
+        let value = self.expression(tokens, &mut position)?;
+        if position < tokens.len() {
+            return Err(self.error(format!("unexpected '{}'", tokens[position].text)));
+        }
+        Ok(value)
+    }
+
+    fn expression(&self, tokens: &[Token], position: &mut usize) -> Result<f64, Chip8Error> {
+        let left = self.term(tokens, position)?;
+        let Some(operator) = tokens.get(*position).map(|token| token.text.as_str()) else {
+            return Ok(left);
+        };
+        if operator == ")" {
+            return Ok(left);
+        }
+        *position += 1;
+        let right = self.expression(tokens, position)?;
+        // Bitwise operators work on 32-bit integers, as in Octo's JavaScript
+        let as_int = |value: f64| value as i64 as i32;
+        Ok(match operator {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (as_int(left) & as_int(right)) as f64,
+            "|" => (as_int(left) | as_int(right)) as f64,
+            "^" => (as_int(left) ^ as_int(right)) as f64,
+            "<<" => as_int(left).wrapping_shl(as_int(right) as u32) as f64,
+            ">>" => as_int(left).wrapping_shr(as_int(right) as u32) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as u8 as f64,
+            "<=" => (left <= right) as u8 as f64,
+            "==" => (left == right) as u8 as f64,
+            "!=" => (left != right) as u8 as f64,
+            ">=" => (left >= right) as u8 as f64,
+            ">" => (left > right) as u8 as f64,
+            _ => return Err(self.error(format!("unknown operator '{operator}'"))),
+        })
+    }
+
+    fn term(&self, tokens: &[Token], position: &mut usize) -> Result<f64, Chip8Error> {
+        let token = tokens
+            .get(*position)
+            .ok_or_else(|| self.error("incomplete expression"))?;
+        *position += 1;
+        let text = token.text.as_str();
+        if text == "(" {
+            let value = self.expression(tokens, position)?;
+            if tokens.get(*position).map(|token| token.text.as_str()) != Some(")") {
+                return Err(self.error("expected ')'"));
+            }
+            *position += 1;
+            return Ok(value);
+        }
+        if let Some(value) = parse_number(text) {
+            return Ok(value);
+        }
+        let unary: Option<fn(f64) -> f64> = match text {
+            "-" => Some(|value| -value),
+            "~" => Some(|value| !(value as i64 as i32) as f64),
+            "!" => Some(|value| (value == 0.0) as u8 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.term(tokens, position)?));
+        }
+        if text == "@" {
+            let address = self.term(tokens, position)? as usize;
+            return Ok(address
+                .checked_sub(PROGRAM_START)
+                .and_then(|idx| self.rom.get(idx))
+                .copied()
+                .unwrap_or(0) as f64);
+        }
+        match text {
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => self
+                .defined(text)
+                .ok_or_else(|| self.error(format!("undefined name '{text}'"))),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, Chip8Error> {
+        let x = self.register()? as u16;
+        let token = self.next()?;
+        let comparison = Comparison::parse(&token.text)
+            .ok_or_else(|| self.error(format!("unknown comparison '{}'", token.text)))?;
+        let operand = match comparison {
+            Comparison::Key | Comparison::NotKey => None,
+            _ => Some(self.operand()?),
+        };
+        Ok((x, comparison, operand))
+    }
+
+    /// Writes instructions after which the next instruction only runs if the condition holds
+    fn skip_unless(&mut self, (x, comparison, operand): Condition) {
+        match (comparison, operand) {
+            (Comparison::Key, _) => self.instruction(0xE0A1 | x << 8),
+            (Comparison::NotKey, _) => self.instruction(0xE09E | x << 8),
+            (Comparison::Equal, Some(Operand::Value(n))) => {
+                self.instruction(0x4000 | x << 8 | n as u16)
+            }
+            (Comparison::Equal, Some(Operand::Register(y))) => {
+                self.instruction(0x9000 | x << 8 | (y as u16) << 4)
+            }
+            (Comparison::NotEqual, Some(Operand::Value(n))) => {
+                self.instruction(0x3000 | x << 8 | n as u16)
+            }
+            (Comparison::NotEqual, Some(Operand::Register(y))) => {
+                self.instruction(0x5000 | x << 8 | (y as u16) << 4)
+            }
+            (comparison, Some(operand)) => {
+                // Relational comparisons subtract into vf and test the carry flag
+                let subtract_y = matches!(comparison, Comparison::Less | Comparison::GreaterEqual);
+                match operand {
+                    Operand::Register(y) => {
+                        let y = y as u16;
+                        self.instruction(0x8F00 | x << 4);
+                        if subtract_y {
+                            self.instruction(0x8F05 | y << 4);
+                        } else {
+                            self.instruction(0x8F07 | y << 4);
+                        }
+                    }
+                    Operand::Value(n) => {
+                        self.instruction(0x6F00 | n as u16);
+                        if subtract_y {
+                            self.instruction(0x8F07 | x << 4);
+                        } else {
+                            self.instruction(0x8F05 | x << 4);
+                        }
+                    }
+                }
+                let carry = matches!(comparison, Comparison::GreaterEqual | Comparison::LessEqual);
+                self.instruction(0x4F00 | carry as u16);
+            }
+            (_, None) => unreachable!(),
+        }
+    }
+
+    fn jump_placeholder(&mut self) -> usize {
+        let at = self.here;
+        self.instruction(0x1000);
+        at
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), Chip8Error> {
+        if token.string {
+            return Err(self.error(format!("unexpected string \"{}\"", token.text)));
+        }
+        let text = token.text.as_str();
+        match text {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(name, self.here)?;
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                self.constants.insert(name, value);
+            }
+            ":calc" => {
+                let name = self.name()?;
+                let expression = self.block()?;
+                let value = self.evaluate(&expression)?;
+                self.constants.insert(name, value);
+            }
+            ":assert" => {
+                let message = match self.tokens.front() {
+                    Some(token) if token.string => self.next()?.text,
+                    _ => "assertion failed".to_string(),
+                };
+                let expression = self.block()?;
+                if self.evaluate(&expression)? == 0.0 {
+                    return Err(self.error(message));
+                }
+            }
+            ":unpack" => {
+                let hi = self.aliases["unpack-hi"] as u16;
+                let lo = self.aliases["unpack-lo"] as u16;
+                if self.peek_is("long") {
+                    self.next()?;
+                    let [high, low] = self.address(Fixup::UnpackLong)?.to_be_bytes();
+                    self.instruction(0x6000 | hi << 8 | high as u16);
+                    self.instruction(0x6000 | lo << 8 | low as u16);
+                } else {
+                    let nybble = self.nybble()?;
+                    let [high, low] = self.address(Fixup::Unpack)?.to_be_bytes();
+                    self.instruction(0x6000 | hi << 8 | nybble << 4 | high as u16);
+                    self.instruction(0x6000 | lo << 8 | low as u16);
+                }
+            }
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(name, self.here + 1)?;
+            }
+            ":org" => {
+                let address = self.value()?;
+                if !(PROGRAM_START as f64..0x10000 as f64).contains(&address) {
+                    return Err(self.error(format!("address {address} is out of range")));
+                }
+                self.here = address as usize;
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.write(byte);
+            }
+            ":call" => {
+                let address = self.address(Fixup::Address)?;
+                self.instruction(0x2000 | address);
+            }
+            // Debugging directives for Octo's own emulator
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ":macro" => {
+                let name = self.name()?;
+                let mut args = Vec::new();
+                while !self.peek_is("{") {
+                    args.push(self.name()?);
+                }
+                let body = self.block()?;
+                self.macros.insert(
+                    name,
+                    Macro {
+                        args,
+                        body,
+                        calls: 0,
+                    },
+                );
+            }
+            ":stringmode" => {
+                let name = self.name()?;
+                let alphabet = self.next()?;
+                if !alphabet.string {
+                    return Err(self.error("expected a string of characters"));
+                }
+                let body = self.block()?;
+                let mode = self.string_modes.entry(name).or_default();
+                for (idx, c) in alphabet.text.chars().enumerate() {
+                    mode.insert(c, (idx, body.clone()));
+                }
+            }
+            ";" | "return" => self.instruction(0x00EE),
+            "clear" => self.instruction(0x00E0),
+            "exit" => self.instruction(0x00FD),
+            "lores" => self.instruction(0x00FE),
+            "hires" => self.instruction(0x00FF),
+            "scroll-left" => self.instruction(0x00FC),
+            "scroll-right" => self.instruction(0x00FB),
+            "scroll-down" => {
+                let n = self.nybble()?;
+                self.instruction(0x00C0 | n);
+            }
+            "scroll-up" => {
+                let n = self.nybble()?;
+                self.instruction(0x00D0 | n);
+            }
+            "audio" => self.instruction(0xF002),
+            "plane" => {
+                let n = self.nybble()?;
+                self.instruction(0xF001 | n << 8);
+            }
+            "bcd" => {
+                let x = self.register()? as u16;
+                self.instruction(0xF033 | x << 8);
+            }
+            "save" | "load" => {
+                let x = self.register()? as u16;
+                if self.peek_is("-") {
+                    self.next()?;
+                    let y = self.register()? as u16;
+                    let op = if text == "save" { 2 } else { 3 };
+                    self.instruction(0x5000 | x << 8 | y << 4 | op);
+                } else {
+                    let op = if text == "save" { 0x55 } else { 0x65 };
+                    self.instruction(0xF000 | x << 8 | op);
+                }
+            }
+            "saveflags" => {
+                let x = self.register()? as u16;
+                self.instruction(0xF075 | x << 8);
+            }
+            "loadflags" => {
+                let x = self.register()? as u16;
+                self.instruction(0xF085 | x << 8);
+            }
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.nybble()?;
+                self.instruction(0xD000 | x << 8 | y << 4 | n);
+            }
+            "jump" | "jump0" | "native" => {
+                let address = self.address(Fixup::Address)?;
+                let op = match text {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000,
+                };
+                self.instruction(op | address);
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()? as u16;
+                let op = match text {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                };
+                self.instruction(0xF000 | x << 8 | op);
+            }
+            "i" => {
+                let op = self.next()?;
+                match op.text.as_str() {
+                    ":=" if self.peek_is("hex") || self.peek_is("bighex") => {
+                        let op = if self.next()?.text == "hex" {
+                            0x29
+                        } else {
+                            0x30
+                        };
+                        let x = self.register()? as u16;
+                        self.instruction(0xF000 | x << 8 | op);
+                    }
+                    ":=" if self.peek_is("long") => {
+                        self.next()?;
+                        self.instruction(0xF000);
+                        let address = self.address(Fixup::Long)?;
+                        self.instruction(address);
+                    }
+                    ":=" => {
+                        let address = self.address(Fixup::Address)?;
+                        self.instruction(0xA000 | address);
+                    }
+                    "+=" => {
+                        let x = self.register()? as u16;
+                        self.instruction(0xF01E | x << 8);
+                    }
+                    other => return Err(self.error(format!("unknown operator 'i {other}'"))),
+                }
+            }
+            "if" => {
+                let (x, comparison, operand) = self.condition()?;
+                let keyword = self.next()?;
+                match keyword.text.as_str() {
+                    "then" => self.skip_unless((x, comparison, operand)),
+                    // The jump to `else` or `end` only runs if the condition fails
+                    "begin" => {
+                        self.skip_unless((x, comparison.negate(), operand));
+                        let at = self.jump_placeholder();
+                        self.branches.push(at);
+                    }
+                    other => {
+                        return Err(
+                            self.error(format!("expected 'then' or 'begin', found '{other}'"))
+                        )
+                    }
+                }
+            }
+            "else" => {
+                let previous = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| self.error("'else' without 'if ... begin'"))?;
+                let at = self.jump_placeholder();
+                self.patch(previous, Fixup::Address, self.here as u16);
+                self.branches.push(at);
+            }
+            "end" => {
+                let previous = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| self.error("'end' without 'if ... begin'"))?;
+                self.patch(previous, Fixup::Address, self.here as u16);
+            }
+            "loop" => self.loops.push((self.here, Vec::new())),
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(self.error("'while' outside a loop"));
+                }
+                let (x, comparison, operand) = self.condition()?;
+                self.skip_unless((x, comparison.negate(), operand));
+                let at = self.jump_placeholder();
+                self.loops.last_mut().unwrap().1.push(at);
+            }
+            "again" => {
+                let (start, exits) = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| self.error("'again' without 'loop'"))?;
+                self.instruction(0x1000 | start as u16);
+                for exit in exits {
+                    self.patch(exit, Fixup::Address, self.here as u16);
+                }
+            }
+            _ if self.is_register(text) => {
+                self.tokens.push_front(token);
+                self.register_statement()?;
+            }
+            _ if self.macros.contains_key(text) => self.expand_macro(text)?,
+            _ if self.string_modes.contains_key(text) => self.expand_string(text)?,
+            _ => {
+                if let Some(value) =
+                    parse_number(text).or_else(|| self.constants.get(text).copied())
+                {
+                    // Bare numbers are data
+                    let byte = self.to_byte(value)?;
+                    self.write(byte);
+                } else {
+                    // Any other name calls a subroutine
+                    self.tokens.push_front(token);
+                    let address = self.address(Fixup::Address)?;
+                    self.instruction(0x2000 | address);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn register_statement(&mut self) -> Result<(), Chip8Error> {
+        let x = self.register()? as u16;
+        let op = self.next()?;
+        let op = op.text.as_str();
+        match op {
+            ":=" if self.peek_is("random") => {
+                self.next()?;
+                let n = self.byte()? as u16;
+                self.instruction(0xC000 | x << 8 | n);
+                return Ok(());
+            }
+            ":=" if self.peek_is("key") => {
+                self.next()?;
+                self.instruction(0xF00A | x << 8);
+                return Ok(());
+            }
+            ":=" if self.peek_is("delay") => {
+                self.next()?;
+                self.instruction(0xF007 | x << 8);
+                return Ok(());
+            }
+            _ => {}
+        }
+        match (op, self.operand()?) {
+            (":=", Operand::Register(y)) => self.instruction(0x8000 | x << 8 | (y as u16) << 4),
+            (":=", Operand::Value(n)) => self.instruction(0x6000 | x << 8 | n as u16),
+            ("+=", Operand::Value(n)) => self.instruction(0x7000 | x << 8 | n as u16),
+            ("-=", Operand::Value(n)) => {
+                self.instruction(0x7000 | x << 8 | n.wrapping_neg() as u16)
+            }
+            (op, Operand::Register(y)) => {
+                let op = match op {
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    "+=" => 0x4,
+                    "-=" => 0x5,
+                    ">>=" => 0x6,
+                    "=-" => 0x7,
+                    "<<=" => 0xE,
+                    _ => return Err(self.error(format!("unknown operator '{op}'"))),
+                };
+                self.instruction(0x8000 | x << 8 | (y as u16) << 4 | op);
+            }
+            (op, Operand::Value(_)) => {
+                return Err(self.error(format!("'{op}' needs a register operand")))
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues `expansion` to be assembled next
+    fn push_expansion(&mut self, expansion: Vec<Token>) -> Result<(), Chip8Error> {
+        self.expanded += expansion.len();
+        if self.expanded > EXPANSION_LIMIT {
+            return Err(self.error("macros expand too far, possibly recursively"));
+        }
+        for token in expansion.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), Chip8Error> {
+        let count = self.macros[name].args.len();
+        let mut args = HashMap::new();
+        for idx in 0..count {
+            let arg = self.next()?;
+            args.insert(self.macros[name].args[idx].clone(), arg);
+        }
+        let line = self.line;
+        let definition = self.macros.get_mut(name).unwrap();
+        definition.calls += 1;
+        let body: Vec<Token> = definition
+            .body
+            .iter()
+            .map(|token| match args.get(&token.text) {
+                Some(arg) if !token.string => arg.clone(),
+                _ => Token {
+                    line,
+                    ..token.clone()
+                },
+            })
+            .collect();
+        self.constants
+            .insert("CALLS".to_string(), definition.calls as f64);
+        self.push_expansion(body)
+    }
+
+    fn expand_string(&mut self, name: &str) -> Result<(), Chip8Error> {
+        let text = self.next()?;
+        if !text.string {
+            return Err(self.error(format!("'{name}' needs a string")));
+        }
+        let mode = &self.string_modes[name];
+        let mut expansion = Vec::new();
+        for (index, c) in text.text.chars().enumerate() {
+            let (value, body) = mode
+                .get(&c)
+                .ok_or_else(|| self.error(format!("'{name}' has no entry for {c:?}")))?;
+            expansion.extend(body.iter().map(|token| {
+                let text = match token.text.as_str() {
+                    _ if token.string => token.text.clone(),
+                    "CHAR" => (c as u32).to_string(),
+                    "INDEX" => index.to_string(),
+                    "VALUE" => value.to_string(),
+                    _ => token.text.clone(),
+                };
+                Token {
+                    text,
+                    ..token.clone()
+                }
+            }));
+        }
+        self.push_expansion(expansion)
+    }
+
+    fn finish(self) -> Result<Vec<u8>, Chip8Error> {
+        if !self.branches.is_empty() {
+            return Err(self.error("'if ... begin' without 'end'"));
+        }
+        if !self.loops.is_empty() {
+            return Err(self.error("'loop' without 'again'"));
+        }
+        if let Some((name, references)) = self.unresolved.iter().next() {
+            let line = references.first().map_or(self.line, |&(_, _, line)| line);
+            return Err(assembly_error(line, format!("undefined name '{name}'")));
+        }
+        Ok(self.rom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_ok(source: &str) -> Vec<u8> {
+        match assemble(source) {
+            Ok(rom) => rom,
+            Err(error) => panic!("{error}"),
+        }
+    }
+
+    fn error_line(source: &str) -> usize {
+        match assemble(source) {
+            Err(Chip8Error::Assembly { line, .. }) => line,
+            other => panic!("expected an assembly error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn labels_and_forward_references() {
+        let rom = assemble_ok(
+            ": main
+                jump later
+                :call sub
+            : sub
+                return
+            : later
+                sub",
+        );
+        assert_eq!(
+            rom,
+            [0x12, 0x02, 0x12, 0x08, 0x22, 0x06, 0x00, 0xEE, 0x22, 0x06]
+        );
+    }
+
+    #[test]
+    fn undefined_labels_report_their_line() {
+        assert_eq!(error_line(": main\n\n  jump nowhere"), 3);
+        assert_eq!(error_line("v0 := 1"), 1);
+    }
+
+    #[test]
+    fn if_else_and_loops() {
+        let rom = assemble_ok(
+            ": main
+                if v0 == 1 then v1 := 2
+                if v0 != v1 begin
+                    v2 := 3
+                else
+                    v2 := 4
+                end
+                loop
+                    v0 += 1
+                    while v0 != 5
+                again",
+        );
+        assert_eq!(
+            rom,
+            [
+                0x12, 0x02, 0x40, 0x01, 0x61, 0x02, 0x90, 0x10, 0x12, 0x0E, 0x62, 0x03, 0x12, 0x10,
+                0x62, 0x04, 0x70, 0x01, 0x40, 0x05, 0x12, 0x18, 0x12, 0x10,
+            ]
+        );
+    }
+
+    #[test]
+    fn relational_comparisons_use_vf() {
+        let rom = assemble_ok(": main if v0 < v1 then v2 := 1");
+        assert_eq!(rom[2..], [0x8F, 0x00, 0x8F, 0x15, 0x4F, 0x00, 0x62, 0x01]);
+        let rom = assemble_ok(": main if v3 >= 7 then v2 := 1");
+        assert_eq!(rom[2..], [0x6F, 0x07, 0x8F, 0x37, 0x4F, 0x01, 0x62, 0x01]);
+    }
+
+    #[test]
+    fn unbalanced_blocks_are_errors() {
+        assert!(assemble(": main if v0 == 1 begin").is_err());
+        assert!(assemble(": main end").is_err());
+        assert!(assemble(": main loop").is_err());
+        assert!(assemble(": main while v0 == 1").is_err());
+    }
+
+    #[test]
+    fn calc_applies_operators_right_to_left() {
+        let rom = assemble_ok(
+            ":const BASE 2
+            :calc A { BASE * 3 + 1 }
+            :calc B { ( BASE * 3 ) + 1 }
+            :calc C { 1 << 4 }
+            :calc D { 1 << 100 }
+            :assert \"A is too big\" { A < 10 }
+            : main
+                v0 := A
+                v1 := B
+                v2 := C
+                v3 := D",
+        );
+        assert_eq!(rom[2..], [0x60, 0x08, 0x61, 0x07, 0x62, 0x10, 0x63, 0x10]);
+        assert!(assemble(":calc A { 5 } :assert { A < 5 } : main").is_err());
+    }
+
+    #[test]
+    fn unpack_patches_forward_references() {
+        let rom = assemble_ok(
+            ": main
+                :unpack 0xA data
+                :unpack long data
+            : data
+                0x12",
+        );
+        assert_eq!(
+            rom[2..],
+            [0x60, 0xA2, 0x61, 0x0A, 0x60, 0x02, 0x61, 0x0A, 0x12]
+        );
+    }
+
+    #[test]
+    fn macros_substitute_arguments_and_count_calls() {
+        let rom = assemble_ok(
+            ":macro set-both X Y { v0 := X v1 := Y }
+            :macro count { v2 := CALLS }
+            : main
+                set-both 1 2
+                set-both 3 v4
+                count count",
+        );
+        assert_eq!(
+            rom[2..],
+            [0x60, 0x01, 0x61, 0x02, 0x60, 0x03, 0x81, 0x40, 0x62, 0x01, 0x62, 0x02]
+        );
+    }
+
+    #[test]
+    fn recursive_macros_fail() {
+        assert!(assemble(":macro m { m } : main m").is_err());
+        assert!(assemble(":stringmode s \"a\" { s \"a\" } : main s \"a\"").is_err());
+    }
+
+    #[test]
+    fn string_modes() {
+        let rom = assemble_ok(
+            ":stringmode text \"ABC\" { :byte { VALUE + 1 } }
+            :stringmode ascii \"AB\" { :byte CHAR :byte INDEX }
+            : main
+                text \"CAB\"
+                ascii \"BA\"",
+        );
+        assert_eq!(rom[2..], [3, 1, 2, 0x42, 0, 0x41, 1]);
+        assert!(assemble(":stringmode text \"AB\" { :byte VALUE } : main text \"C\"").is_err());
+    }
+}
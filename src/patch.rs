@@ -0,0 +1,348 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Largest ROM that fits in memory above 0x200, which bounds what a patch may produce
+const MAX_SIZE: usize = 4096 - 0x200;
+
+/// Applies an IPS or BPS patch to `rom`, identified by its header
+pub(crate) fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if let Some(records) = patch.strip_prefix(b"PATCH") {
+        apply_ips(rom, records)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err("not an IPS or BPS patch".to_string())
+    }
+}
+
+/// Patches in `directory` for the ROM with SHA-1 hash `hash`, named `<hash>.ips`, `<hash>.bps`
+/// or `<hash>.<anything>.ips` and so on, in name order
+pub fn find_patches(directory: &Path, hash: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                return false;
+            };
+            let name = name.to_ascii_lowercase();
+            name.split('.').next() == Some(hash)
+                && (name.ends_with(".ips") || name.ends_with(".bps"))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn truncated() -> String {
+    "patch is truncated".to_string()
+}
+
+fn too_large() -> String {
+    format!("patched ROM would be larger than {MAX_SIZE} bytes")
+}
+
+fn apply_ips(rom: &[u8], mut records: &[u8]) -> Result<Vec<u8>, String> {
+    let mut output = rom.to_vec();
+    let mut take = |count: usize| -> Result<&[u8], String> {
+        let (taken, rest) = records.split_at_checked(count).ok_or_else(truncated)?;
+        records = rest;
+        Ok(taken)
+    };
+    loop {
+        let offset = take(3)?;
+        if offset == b"EOF" {
+            // An optional trailing size truncates the output
+            if let Ok(size) = take(3) {
+                let size = (size[0] as usize) << 16 | (size[1] as usize) << 8 | size[2] as usize;
+                output.truncate(size);
+            }
+            return Ok(output);
+        }
+        let offset = (offset[0] as usize) << 16 | (offset[1] as usize) << 8 | offset[2] as usize;
+        let size = take(2)?;
+        let size = (size[0] as usize) << 8 | size[1] as usize;
+        // A size of 0 marks a run of one repeated byte
+        let (length, run) = if size == 0 {
+            let run = take(3)?;
+            ((run[0] as usize) << 8 | run[1] as usize, Some(run[2]))
+        } else {
+            (size, None)
+        };
+        let end = offset
+            .checked_add(length)
+            .filter(|&end| end <= MAX_SIZE)
+            .ok_or_else(too_large)?;
+        if output.len() < end {
+            output.resize(end, 0);
+        }
+        match run {
+            Some(byte) => output[offset..end].fill(byte),
+            None => output[offset..end].copy_from_slice(take(length)?),
+        }
+    }
+}
+
+/// Reads a BPS variable-length number
+fn read_number(patch: &[u8], position: &mut usize) -> Result<usize, String> {
+    let mut value = 0usize;
+    let mut shift = 1usize;
+    loop {
+        let byte = *patch.get(*position).ok_or_else(truncated)?;
+        *position += 1;
+        value = (byte as usize & 0x7F)
+            .checked_mul(shift)
+            .and_then(|part| value.checked_add(part))
+            .ok_or("number in patch is too large")?;
+        if byte & 0x80 != 0 {
+            return Ok(value);
+        }
+        shift = shift
+            .checked_mul(128)
+            .ok_or("number in patch is too large")?;
+        value = value
+            .checked_add(shift)
+            .ok_or("number in patch is too large")?;
+    }
+}
+
+/// Applies a signed relative offset encoded in a BPS number
+fn relative(offset: &mut usize, data: usize) -> Result<(), String> {
+    let distance = data >> 1;
+    *offset = if data & 1 == 0 {
+        offset.checked_add(distance)
+    } else {
+        offset.checked_sub(distance)
+    }
+    .ok_or("patch copies from outside the data")?;
+    Ok(())
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let footer = patch
+        .len()
+        .checked_sub(12)
+        .filter(|&footer| footer >= 4)
+        .ok_or_else(truncated)?;
+    let checksum =
+        |offset: usize| u32::from_le_bytes(patch[offset..offset + 4].try_into().unwrap());
+    if crc32(&patch[..footer + 8]) != checksum(footer + 8) {
+        return Err("patch is corrupt".to_string());
+    }
+    if crc32(rom) != checksum(footer) {
+        return Err("patch is for a different ROM".to_string());
+    }
+    let mut position = 4;
+    let source_size = read_number(patch, &mut position)?;
+    let target_size = read_number(patch, &mut position)?;
+    let metadata_size = read_number(patch, &mut position)?;
+    position = position.saturating_add(metadata_size);
+    if source_size != rom.len() {
+        return Err("patch is for a different ROM".to_string());
+    }
+    if target_size > MAX_SIZE {
+        return Err(too_large());
+    }
+    let mut output = Vec::with_capacity(target_size);
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while position < footer {
+        let data = read_number(patch, &mut position)?;
+        let length = (data >> 2) + 1;
+        // Every action appends `length` bytes, which must stay within the declared size
+        output
+            .len()
+            .checked_add(length)
+            .filter(|&end| end <= target_size)
+            .ok_or("patch writes past the end of the patched ROM")?;
+        match data & 3 {
+            // Source read: copy from the same position in the ROM
+            0 => {
+                let start = output.len();
+                let bytes = start
+                    .checked_add(length)
+                    .and_then(|end| rom.get(start..end))
+                    .ok_or("patch copies from outside the ROM")?;
+                output.extend_from_slice(bytes);
+            }
+            // Target read: copy from the patch
+            1 => {
+                let end = position
+                    .checked_add(length)
+                    .filter(|&end| end <= footer)
+                    .ok_or_else(truncated)?;
+                output.extend_from_slice(&patch[position..end]);
+                position = end;
+            }
+            // Source copy: copy from elsewhere in the ROM
+            2 => {
+                let offset = read_number(patch, &mut position)?;
+                relative(&mut source_offset, offset)?;
+                let end = source_offset
+                    .checked_add(length)
+                    .filter(|&end| end <= rom.len())
+                    .ok_or("patch copies from outside the ROM")?;
+                output.extend_from_slice(&rom[source_offset..end]);
+                source_offset = end;
+            }
+            // Target copy: copy from earlier output, one byte at a time so runs can overlap
+            _ => {
+                let offset = read_number(patch, &mut position)?;
+                relative(&mut target_offset, offset)?;
+                for _ in 0..length {
+                    let byte = *output
+                        .get(target_offset)
+                        .ok_or("patch copies from outside the output")?;
+                    output.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if output.len() != target_size || crc32(&output) != checksum(footer + 4) {
+        return Err("patched ROM does not match the patch checksum".to_string());
+    }
+    Ok(output)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = b"ABCDEF";
+
+    #[test]
+    fn ips_records() {
+        let patch = b"PATCH\x00\x00\x01\x00\x02xy\x00\x00\x04\x00\x00\x00\x03zEOF";
+        assert_eq!(apply(ROM, patch).unwrap(), b"AxyDzzz");
+    }
+
+    #[test]
+    fn ips_truncate() {
+        let patch = b"PATCH\x00\x00\x00\x00\x01aEOF\x00\x00\x03";
+        assert_eq!(apply(ROM, patch).unwrap(), b"aBC");
+    }
+
+    #[test]
+    fn ips_errors() {
+        assert_eq!(
+            apply(ROM, b"PATCH\x00\x00\x00\x00\x02a").unwrap_err(),
+            "patch is truncated"
+        );
+        // A record past the end of memory would otherwise allocate up to 16 MiB
+        assert_eq!(
+            apply(ROM, b"PATCH\xff\xff\xff\x00\x01aEOF").unwrap_err(),
+            too_large()
+        );
+    }
+
+    fn encode_number(mut value: usize, output: &mut Vec<u8>) {
+        loop {
+            let low = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                output.push(0x80 | low);
+                return;
+            }
+            output.push(low);
+            value -= 1;
+        }
+    }
+
+    /// Builds a BPS patch from `source` to `target` out of (action, length, operand) triples,
+    /// where the operand is the bytes of a target read or the encoded offset of a copy
+    fn bps(source: &[u8], target: &[u8], actions: &[(usize, usize, &[u8])]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        encode_number(source.len(), &mut patch);
+        encode_number(target.len(), &mut patch);
+        encode_number(0, &mut patch);
+        for &(action, length, operand) in actions {
+            encode_number((length - 1) << 2 | action, &mut patch);
+            match action {
+                1 => patch.extend_from_slice(operand),
+                2 | 3 => encode_number(operand[0] as usize, &mut patch),
+                _ => {}
+            }
+        }
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    /// Source read "AB", target read "xy", source copy "EF" from 4 bytes on, then an
+    /// overlapping target copy of 3 bytes from the start of the output
+    const ACTIONS: &[(usize, usize, &[u8])] =
+        &[(0, 2, b""), (1, 2, b"xy"), (2, 2, &[8]), (3, 3, &[0])];
+    const TARGET: &[u8] = b"ABxyEFABx";
+
+    #[test]
+    fn bps_actions() {
+        let patch = bps(ROM, TARGET, ACTIONS);
+        assert_eq!(apply(ROM, &patch).unwrap(), TARGET);
+    }
+
+    #[test]
+    fn bps_checksums() {
+        let mut patch = bps(ROM, TARGET, ACTIONS);
+        assert_eq!(
+            apply(b"ABCDEG", &patch).unwrap_err(),
+            "patch is for a different ROM"
+        );
+
+        let wrong_target = bps(ROM, b"ABxyEFABz", ACTIONS);
+        assert_eq!(
+            apply(ROM, &wrong_target).unwrap_err(),
+            "patched ROM does not match the patch checksum"
+        );
+
+        patch[8] ^= 1;
+        assert_eq!(apply(ROM, &patch).unwrap_err(), "patch is corrupt");
+    }
+
+    #[test]
+    fn bps_numbers() {
+        let mut patch = Vec::new();
+        encode_number(0x1234_5678, &mut patch);
+        assert_eq!(read_number(&patch, &mut 0), Ok(0x1234_5678));
+        // Continuation bytes beyond the width of usize overflow rather than wrap
+        let mut position = 0;
+        assert_eq!(
+            read_number(&[0x7F; 16], &mut position).unwrap_err(),
+            "number in patch is too large"
+        );
+    }
+
+    #[test]
+    fn bps_bounds() {
+        let target = vec![0; MAX_SIZE + 1];
+        let patch = bps(ROM, &target, &[]);
+        assert_eq!(apply(ROM, &patch).unwrap_err(), too_large());
+
+        // A target copy longer than the declared size would otherwise grow the output forever
+        let patch = bps(ROM, b"A", &[(0, 1, b""), (3, 1 << 20, &[0])]);
+        assert_eq!(
+            apply(ROM, &patch).unwrap_err(),
+            "patch writes past the end of the patched ROM"
+        );
+    }
+}
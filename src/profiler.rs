@@ -0,0 +1,131 @@
+use crate::{disassemble, CPU};
+use egui::{Color32, Grid, Ui};
+use std::collections::BTreeMap;
+
+/// Number of rows shown in each profiler table
+const TABLE_ROWS: usize = 20;
+
+#[derive(Clone, Copy, Default)]
+struct Subroutine {
+    calls: u64,
+    /// Cycles spent inside the subroutine, including nested calls
+    cycles: u64,
+}
+
+/// Execution counts per address and cycle counts per subroutine
+pub(crate) struct Profiler {
+    counts: Vec<u64>,
+    /// Highest value in `counts`
+    hottest: u64,
+    subroutines: BTreeMap<u16, Subroutine>,
+    /// Entry address and starting cycle of each subroutine currently on the stack
+    frames: Vec<(u16, u64)>,
+}
+
+impl Profiler {
+    pub(crate) fn new() -> Self {
+        Self {
+            counts: vec![0; 4096],
+            hottest: 0,
+            subroutines: BTreeMap::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Accounts for an instruction that finished executing at cycle `cycles`
+    pub(crate) fn instruction(&mut self, address: u16, opcode: u16, cycles: u64) {
+        self.counts[address as usize] += 1;
+        self.hottest = self.hottest.max(self.counts[address as usize]);
+        if opcode & 0xF000 == 0x2000 {
+            // Unbalanced calls would otherwise grow this forever
+            if self.frames.len() == 16 {
+                self.frames.remove(0);
+            }
+            self.frames.push((opcode & 0x0FFF, cycles - 1));
+        } else if opcode == 0x00EE {
+            if let Some((entry, start)) = self.frames.pop() {
+                let subroutine = self.subroutines.entry(entry).or_default();
+                subroutine.calls += 1;
+                subroutine.cycles += cycles - start;
+            }
+        }
+    }
+
+    /// Heatmap colour for the gutter next to `address`
+    pub(crate) fn heat(&self, address: usize) -> Color32 {
+        let count = self.counts[address];
+        if count == 0 {
+            return Color32::TRANSPARENT;
+        }
+        // Logarithmic so that cold code is still distinguishable from unexecuted code
+        let heat = (count as f32).ln_1p() / (self.hottest as f32).ln_1p();
+        Color32::from_rgb(
+            (64.0 + 191.0 * heat) as u8,
+            (64.0 * (1.0 - heat)) as u8,
+            (192.0 * (1.0 - heat)) as u8,
+        )
+    }
+}
+
+impl CPU {
+    pub(crate) fn profiler_ui(&mut self, ui: &mut Ui) {
+        let Some(profiler) = &self.profiler else {
+            return;
+        };
+        let total: u64 = profiler.counts.iter().sum();
+        let mut hotspots: Vec<(usize, u64)> = profiler
+            .counts
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        hotspots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hotspots.truncate(TABLE_ROWS);
+        let mut subroutines: Vec<(u16, Subroutine)> = profiler
+            .subroutines
+            .iter()
+            .map(|(&entry, &subroutine)| (entry, subroutine))
+            .collect();
+        subroutines.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        subroutines.truncate(TABLE_ROWS);
+        ui.collapsing("Hotspots", |ui| {
+            Grid::new("hotspots").striped(true).show(ui, |ui| {
+                ui.label("Address");
+                ui.label("Count");
+                ui.label("%");
+                ui.label("Instruction");
+                ui.end_row();
+                for (address, count) in hotspots {
+                    ui.label(format!("0x{address:04x}"));
+                    ui.label(count.to_string());
+                    ui.label(format!("{:.1}", count as f64 * 100.0 / total as f64));
+                    ui.label(disassemble(self.fetch(address)).as_ref());
+                    ui.end_row();
+                }
+            });
+        });
+        ui.collapsing("Subroutines", |ui| {
+            Grid::new("subroutines").striped(true).show(ui, |ui| {
+                ui.label("Address");
+                ui.label("Calls");
+                ui.label("Cycles");
+                ui.label("Cycles/call");
+                ui.end_row();
+                for (entry, subroutine) in subroutines {
+                    ui.label(format!("0x{entry:04x}"));
+                    ui.label(subroutine.calls.to_string());
+                    ui.label(subroutine.cycles.to_string());
+                    ui.label(format!(
+                        "{:.1}",
+                        subroutine.cycles as f64 / subroutine.calls as f64
+                    ));
+                    ui.end_row();
+                }
+            });
+        });
+        if ui.button("Reset Profiler").clicked() {
+            self.profiler = Some(Profiler::new());
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use serde::Deserialize;
+
+/// Interpreter a ROM was written for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Platform {
+    #[serde(rename = "chip-8")]
+    Chip8,
+    #[serde(rename = "schip")]
+    Superchip,
+    #[serde(rename = "xo-chip")]
+    XoChip,
+    #[serde(rename = "chip-8x")]
+    Chip8x,
+}
+
+impl Platform {
+    /// Quirks for a ROM written for the platform, starting from `base`, those of the config file.
+    /// Instructions the platform adds to CHIP-8 are illegal when they are reached.
+    pub fn quirks(self, base: Quirks) -> Quirks {
+        match self {
+            // Most CHIP-8 ROMs in circulation were written for CHIP-48 rather than the COSMAC VIP,
+            // which is what the baseline quirks suit
+            Platform::Chip8 | Platform::Chip8x => base,
+            Platform::Superchip => Quirks {
+                vf_reset: false,
+                shift_vy: false,
+                increment_index: false,
+                jump_vx: true,
+                clip: true,
+                ..base
+            },
+            // As in Octo, where XO-CHIP was defined
+            Platform::XoChip => Quirks {
+                vf_reset: false,
+                shift_vy: true,
+                increment_index: true,
+                jump_vx: false,
+                clip: false,
+                ..base
+            },
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "CHIP-8",
+            Platform::Superchip => "SUPER-CHIP",
+            Platform::XoChip => "XO-CHIP",
+            Platform::Chip8x => "CHIP-8X",
+        }
+    }
+}
+
+/// When `Fx0A` finishes waiting for a key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyWait {
+    /// As soon as a key is pressed
+    Press,
+    /// Once the pressed key is released, as on the COSMAC VIP
+    #[default]
+    Release,
+}
+
+/// Behaviours that differ between CHIP-8 interpreters
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quirks {
+    pub key_wait: KeyWait,
+    /// Write VF after the result in 8xy4-8xyE, so that the flag wins when x is F, as the
+    /// original interpreters and Octo do. Otherwise the result overwrites the flag
+    pub flag_last: bool,
+    /// Reset VF to 0 after 8xy1, 8xy2 and 8xy3
+    pub vf_reset: bool,
+    /// Shift Vy into Vx in 8xy6 and 8xyE, rather than shifting Vx in place
+    pub shift_vy: bool,
+    /// Leave I pointing past the last register transferred by Fx55 and Fx65
+    pub increment_index: bool,
+    /// Jump to xnn + Vx in Bxnn, rather than nnn + V0
+    pub jump_vx: bool,
+    /// Cut sprites off at the edges of the screen, rather than wrapping them around
+    pub clip: bool,
+}
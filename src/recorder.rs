@@ -0,0 +1,221 @@
+use crate::{Chip8Error, CPU};
+use gif::{Encoder, Frame, Repeat};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use serde::Deserialize;
+use std::{
+    borrow::Cow,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+const SAMPLE_RATE: u32 = 44_100;
+/// Audio samples per 60 Hz timer tick
+const SAMPLES_PER_FRAME: u32 = SAMPLE_RATE / 60;
+const TONE_FREQUENCY: u32 = 440;
+/// Shortest GIF frame delay in hundredths of a second that browsers play back as written
+const MIN_GIF_DELAY: u64 = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordingFormat {
+    /// Animated GIF
+    #[default]
+    Gif,
+    /// Directory of numbered PNG frames plus a WAV file of the beeper
+    Frames,
+}
+
+enum Output {
+    Gif {
+        encoder: Encoder<BufWriter<File>>,
+        width: u16,
+        height: u16,
+        /// Frame waiting for its delay to be known, with its start time in hundredths of a second
+        pending: Option<(Vec<u8>, u64)>,
+    },
+    Frames {
+        directory: PathBuf,
+        audio: WavWriter<BufWriter<File>>,
+    },
+}
+
+/// Captures the framebuffer on every timer tick, so that recordings play at 60 fps of
+/// emulated time regardless of the host frame rate
+pub(crate) struct Recorder {
+    output: Output,
+    scale: u32,
+    frames: u64,
+}
+
+fn recording_error(error: impl std::error::Error + Send + Sync + 'static) -> Chip8Error {
+    Chip8Error::Recording(Box::new(error))
+}
+
+impl Recorder {
+    pub(crate) fn create(
+        cpu: &CPU,
+        path: &Path,
+        format: RecordingFormat,
+        scale: u32,
+    ) -> Result<Self, Chip8Error> {
+        let scale = scale.max(1);
+        let output = match format {
+            RecordingFormat::Gif => {
+                // Frames after a resolution change are resampled to the initial size
+                let width = (cpu.display_width as u32 * scale).min(u16::MAX as u32) as u16;
+                let height = (cpu.display_height as u32 * scale).min(u16::MAX as u32) as u16;
+                let palette: Vec<u8> = [
+                    cpu.settings.palette.background(),
+                    cpu.settings.palette.foreground(),
+                ]
+                .into_iter()
+                .flat_map(|colour| {
+                    let [r, g, b, _]: [u8; 4] = colour.into();
+                    [r, g, b]
+                })
+                .collect();
+                let file = File::create(path).map_err(recording_error)?;
+                let mut encoder = Encoder::new(BufWriter::new(file), width, height, &palette)
+                    .map_err(recording_error)?;
+                encoder
+                    .set_repeat(Repeat::Infinite)
+                    .map_err(recording_error)?;
+                Output::Gif {
+                    encoder,
+                    width,
+                    height,
+                    pending: None,
+                }
+            }
+            RecordingFormat::Frames => {
+                fs::create_dir_all(path).map_err(recording_error)?;
+                let spec = WavSpec {
+                    channels: 1,
+                    sample_rate: SAMPLE_RATE,
+                    bits_per_sample: 16,
+                    sample_format: SampleFormat::Int,
+                };
+                let audio =
+                    WavWriter::create(path.join("audio.wav"), spec).map_err(recording_error)?;
+                Output::Frames {
+                    directory: path.to_path_buf(),
+                    audio,
+                }
+            }
+        };
+        Ok(Self {
+            output,
+            scale,
+            frames: 0,
+        })
+    }
+
+    /// Time since the recording started in hundredths of a second
+    fn centiseconds(&self) -> u64 {
+        self.frames * 100 / 60
+    }
+
+    pub(crate) fn frame(&mut self, cpu: &CPU) -> Result<(), Chip8Error> {
+        let now = self.centiseconds();
+        match &mut self.output {
+            Output::Gif {
+                encoder,
+                width,
+                height,
+                pending,
+            } => {
+                let pixels = sample(cpu, *width, *height);
+                match pending.take() {
+                    // Unchanged frames extend the delay of the previous one
+                    Some((previous, start)) if previous == pixels => {
+                        *pending = Some((previous, start))
+                    }
+                    // 60 fps does not divide into hundredths of a second, so frames that would
+                    // be shown for less than the minimum delay are replaced by their successor
+                    Some((_, start)) if now - start < MIN_GIF_DELAY => {
+                        *pending = Some((pixels, start))
+                    }
+                    Some((previous, start)) => {
+                        write_gif_frame(encoder, *width, *height, previous, now - start)?;
+                        *pending = Some((pixels, now));
+                    }
+                    None => *pending = Some((pixels, now)),
+                }
+            }
+            Output::Frames { directory, audio } => {
+                let path = directory.join(format!("frame-{:05}.png", self.frames + 1));
+                cpu.frame_image(self.scale)
+                    .save_with_format(path, image::ImageFormat::Png)
+                    .map_err(recording_error)?;
+                let half_period = (SAMPLE_RATE / TONE_FREQUENCY / 2) as u64;
+                let start = self.frames * SAMPLES_PER_FRAME as u64;
+                for sample in start..start + SAMPLES_PER_FRAME as u64 {
+                    let level = if cpu.sound_timer == 0 {
+                        0
+                    } else if (sample / half_period).is_multiple_of(2) {
+                        i16::MAX / 4
+                    } else {
+                        -i16::MAX / 4
+                    };
+                    audio.write_sample(level).map_err(recording_error)?;
+                }
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Result<(), Chip8Error> {
+        let now = self.centiseconds();
+        match self.output {
+            Output::Gif {
+                mut encoder,
+                width,
+                height,
+                pending,
+            } => {
+                if let Some((pixels, start)) = pending {
+                    let delay = (now - start).max(MIN_GIF_DELAY);
+                    write_gif_frame(&mut encoder, width, height, pixels, delay)?;
+                }
+                encoder
+                    .into_inner()
+                    .and_then(|mut writer| writer.flush())
+                    .map_err(recording_error)
+            }
+            Output::Frames { audio, .. } => audio.finalize().map_err(recording_error),
+        }
+    }
+}
+
+/// Palette indices of the framebuffer resampled to `width` × `height`
+fn sample(cpu: &CPU, width: u16, height: u16) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = y * cpu.display_height / height;
+        for x in 0..width {
+            let col = x * cpu.display_width / width;
+            pixels.push(cpu.framebuffer[row * cpu.display_width + col] as u8);
+        }
+    }
+    pixels
+}
+
+fn write_gif_frame(
+    encoder: &mut Encoder<BufWriter<File>>,
+    width: u16,
+    height: u16,
+    pixels: Vec<u8>,
+    delay: u64,
+) -> Result<(), Chip8Error> {
+    let frame = Frame {
+        width,
+        height,
+        delay: delay.min(u16::MAX as u64) as u16,
+        buffer: Cow::Owned(pixels),
+        ..Frame::default()
+    };
+    encoder.write_frame(&frame).map_err(recording_error)
+}
@@ -0,0 +1,151 @@
+use crate::{
+    settings::{PersistenceMode, ScalingMode},
+    CPU,
+};
+use macroquad::prelude::*;
+use std::collections::VecDeque;
+
+/// Draws the framebuffer as a single nearest-neighbour scaled texture
+pub(crate) struct Renderer {
+    image: Image,
+    texture: Texture2D,
+    /// Displayed brightness of each pixel between 0 and 1
+    intensity: Vec<f32>,
+    /// Most recent frames, newest last, for blending
+    frames: VecDeque<Vec<bool>>,
+    fullscreen: bool,
+}
+
+impl Renderer {
+    pub(crate) fn new() -> Self {
+        let image = Image::gen_image_color(64, 32, BLACK);
+        let texture = Self::texture(&image);
+        Self {
+            image,
+            texture,
+            intensity: vec![0.0; 64 * 32],
+            frames: VecDeque::new(),
+            fullscreen: false,
+        }
+    }
+
+    fn texture(image: &Image) -> Texture2D {
+        let texture = Texture2D::from_image(image);
+        texture.set_filter(FilterMode::Nearest);
+        texture
+    }
+
+    pub(crate) fn draw(&mut self, cpu: &CPU) {
+        if cpu.settings.fullscreen != self.fullscreen {
+            self.fullscreen = cpu.settings.fullscreen;
+            unsafe { get_internal_gl() }
+                .quad_context
+                .set_fullscreen(self.fullscreen);
+        }
+        if self.image.width() != cpu.display_width || self.image.height() != cpu.display_height {
+            // The resolution changes when hires mode is entered
+            self.texture.delete();
+            self.image =
+                Image::gen_image_color(cpu.display_width as u16, cpu.display_height as u16, BLACK);
+            self.texture = Self::texture(&self.image);
+            self.intensity = vec![0.0; cpu.display_width * cpu.display_height];
+            self.frames.clear();
+        }
+        self.update_intensity(cpu);
+        let on = cpu.settings.palette.foreground();
+        let off = cpu.settings.palette.background();
+        for (pixel, &intensity) in self
+            .image
+            .get_image_data_mut()
+            .iter_mut()
+            .zip(&self.intensity)
+        {
+            *pixel = Color::new(
+                off.r + (on.r - off.r) * intensity,
+                off.g + (on.g - off.g) * intensity,
+                off.b + (on.b - off.b) * intensity,
+                1.0,
+            )
+            .into();
+        }
+        self.texture.update(&self.image);
+        let scaling = cpu.settings.scaling;
+        clear_background(scaling.border);
+        let (width, height) = (cpu.display_width as f32, cpu.display_height as f32);
+        let (scale_x, scale_y) = match scaling.mode {
+            ScalingMode::Stretch => (screen_width() / width, screen_height() / height),
+            ScalingMode::Aspect => {
+                let scale = (screen_width() / width).min(screen_height() / height);
+                (scale, scale)
+            }
+            ScalingMode::Integer => {
+                // Windows smaller than the framebuffer still get one screen pixel per pixel
+                let scale = (screen_width() / width)
+                    .min(screen_height() / height)
+                    .floor()
+                    .max(1.0);
+                (scale, scale)
+            }
+        };
+        let x = ((screen_width() - width * scale_x) / 2.0).round();
+        let y = ((screen_height() - height * scale_y) / 2.0).round();
+        draw_texture_ex(
+            self.texture,
+            x,
+            y,
+            WHITE,
+            DrawTextureParams {
+                dest_size: Some(vec2(width * scale_x, height * scale_y)),
+                ..Default::default()
+            },
+        );
+        // Lines would cover most of each pixel when zoomed out
+        if scaling.grid && scale_x >= 4.0 && scale_y >= 4.0 {
+            let colour = Color::new(0.0, 0.0, 0.0, 0.4);
+            for col in 1..cpu.display_width {
+                let line_x = x + col as f32 * scale_x;
+                draw_line(line_x, y, line_x, y + height * scale_y, 1.0, colour);
+            }
+            for row in 1..cpu.display_height {
+                let line_y = y + row as f32 * scale_y;
+                draw_line(x, line_y, x + width * scale_x, line_y, 1.0, colour);
+            }
+        }
+    }
+
+    fn update_intensity(&mut self, cpu: &CPU) {
+        let persistence = cpu.settings.persistence;
+        let pixels = self.intensity.len();
+        match persistence.mode {
+            PersistenceMode::Off => {
+                for idx in 0..pixels {
+                    self.intensity[idx] = if cpu.framebuffer[idx] { 1.0 } else { 0.0 };
+                }
+            }
+            PersistenceMode::Blend => {
+                let frames = persistence.frames.max(1);
+                while self.frames.len() >= frames {
+                    self.frames.pop_front();
+                }
+                self.frames
+                    .push_back((0..pixels).map(|idx| cpu.framebuffer[idx]).collect());
+                for idx in 0..pixels {
+                    let lit = self.frames.iter().filter(|frame| frame[idx]).count();
+                    self.intensity[idx] = lit as f32 / self.frames.len() as f32;
+                }
+            }
+            PersistenceMode::Decay => {
+                for idx in 0..pixels {
+                    self.intensity[idx] = if cpu.framebuffer[idx] {
+                        1.0
+                    } else {
+                        self.intensity[idx] * persistence.decay.clamp(0.0, 1.0)
+                    };
+                }
+            }
+        }
+        if persistence.mode != PersistenceMode::Blend {
+            self.frames.clear();
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{
+    config::RomConfig,
+    octo,
+    quirks::{Platform, Quirks},
+    settings::HexColour,
+    Chip8Error,
+};
+use gif::{ColorOutput, DecodeOptions};
+use macroquad::file::load_file;
+use serde::Deserialize;
+use std::{
+    fmt,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
+use zip::{result::ZipError, ZipArchive};
+
+/// A ROM file, or a ROM inside a zip archive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RomLocation {
+    pub path: PathBuf,
+    /// Name of the ROM within the archive at `path`
+    pub entry: Option<String>,
+}
+
+impl RomLocation {
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            entry: None,
+        }
+    }
+
+    /// Name used for the ROM, without the extension
+    pub fn name(&self) -> String {
+        let path = match &self.entry {
+            Some(entry) => Path::new(entry),
+            None => &self.path,
+        };
+        path.file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned())
+    }
+}
+
+impl fmt::Display for RomLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(entry) = &self.entry {
+            write!(f, ":{entry}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `path` names a zip archive of ROMs
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// Reads every ROM in a zip archive, sorted by name, opening the archive only once
+pub(crate) fn archive_files(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Chip8Error> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|name| {
+            !name.ends_with('/')
+                && !name.starts_with("__MACOSX/")
+                && !name.rsplit('/').next().unwrap_or(name).starts_with('.')
+        })
+        .map(String::from)
+        .collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let contents = read_file(&mut archive, &name)?;
+            Ok((name, contents))
+        })
+        .collect()
+}
+
+fn read_entry(data: &[u8], entry: &str) -> Result<Vec<u8>, Chip8Error> {
+    read_file(&mut ZipArchive::new(Cursor::new(data))?, entry)
+}
+
+fn read_file(archive: &mut ZipArchive<Cursor<&[u8]>>, entry: &str) -> Result<Vec<u8>, Chip8Error> {
+    let mut file = archive.by_name(entry).map_err(|error| match error {
+        ZipError::FileNotFound => Chip8Error::ArchiveEntry(entry.to_string()),
+        error => Chip8Error::Archive(error),
+    })?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)
+        .map_err(|error| Chip8Error::Archive(error.into()))?;
+    Ok(contents)
+}
+
+/// Program and settings read from a ROM file
+pub(crate) struct RomFile {
+    pub data: Vec<u8>,
+    /// Platform implied by the file extension or embedded options
+    pub platform: Option<Platform>,
+    /// Options embedded in an Octo cartridge
+    pub options: Option<RomConfig>,
+}
+
+/// Platform a ROM file extension is conventionally used for
+fn extension_platform(path: &Path) -> Option<Platform> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "ch8" | "c8" => Some(Platform::Chip8),
+        "sc8" => Some(Platform::Superchip),
+        "xo8" => Some(Platform::XoChip),
+        "c8x" => Some(Platform::Chip8x),
+        _ => None,
+    }
+}
+
+impl RomFile {
+    pub(crate) async fn read(location: &RomLocation) -> Result<Self, Chip8Error> {
+        let data = load_file(&location.path.to_string_lossy()).await?;
+        Self::extract(location, data)
+    }
+
+    /// Reads the ROM at `location` from `data`, the contents of the file at its path
+    pub(crate) fn extract(location: &RomLocation, data: Vec<u8>) -> Result<Self, Chip8Error> {
+        match &location.entry {
+            Some(entry) => Self::parse(Path::new(entry), read_entry(&data, entry)?),
+            None => Self::parse(&location.path, data),
+        }
+    }
+
+    /// Reads `data` as an Octo cartridge if it is a GIF, or as a raw binary otherwise
+    pub(crate) fn parse(path: &Path, data: Vec<u8>) -> Result<Self, Chip8Error> {
+        if data.starts_with(b"GIF8") {
+            return read_cartridge(&data);
+        }
+        Ok(Self {
+            data,
+            platform: extension_platform(path),
+            options: None,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Cartridge {
+    program: String,
+    #[serde(default)]
+    options: OctoOptions,
+}
+
+/// The parts of Octo's emulator options that apply here
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct OctoOptions {
+    /// Instructions per frame
+    tickrate: Option<usize>,
+    background_color: Option<String>,
+    fill_color: Option<String>,
+    fill_color2: Option<String>,
+    blend_color: Option<String>,
+    /// Memory available to the program, which Octo sets from the chosen platform
+    max_size: Option<usize>,
+    /// 8xy6 and 8xyE shift Vx in place
+    shift_quirks: bool,
+    /// Fx55 and Fx65 leave I unchanged
+    load_store_quirks: bool,
+    /// Bxnn jumps to xnn + Vx
+    jump_quirks: bool,
+    /// 8xy1, 8xy2 and 8xy3 reset VF
+    logic_quirks: bool,
+    /// Sprites are clipped at the edges of the screen
+    clip_quirks: bool,
+}
+
+impl OctoOptions {
+    fn platform(&self) -> Option<Platform> {
+        match self.max_size? {
+            3216 => Some(Platform::Chip8),
+            3583 => Some(Platform::Superchip),
+            65024 => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    fn rom_config(&self) -> RomConfig {
+        // Colours are only used up to the first one missing or invalid
+        let colours = [
+            &self.background_color,
+            &self.fill_color,
+            &self.fill_color2,
+            &self.blend_color,
+        ]
+        .into_iter()
+        .map_while(|colour| colour.as_deref()?.parse::<HexColour>().ok())
+        .collect();
+        RomConfig {
+            platform: self.platform(),
+            // Octo compiles register comparisons to arithmetic that relies on this order
+            quirks: Some(Quirks {
+                flag_last: true,
+                shift_vy: !self.shift_quirks,
+                increment_index: !self.load_store_quirks,
+                jump_vx: self.jump_quirks,
+                vf_reset: self.logic_quirks,
+                clip: self.clip_quirks,
+                ..Quirks::default()
+            }),
+            speed: self.tickrate,
+            colours,
+            ..RomConfig::default()
+        }
+    }
+}
+
+/// Octo cartridges hide a length-prefixed JSON payload holding the program source and options
+/// in the low bits of each pixel's palette index, continuing across frames
+fn read_cartridge(data: &[u8]) -> Result<RomFile, Chip8Error> {
+    let mut options = DecodeOptions::new();
+    options.set_color_output(ColorOutput::Indexed);
+    let mut decoder = options
+        .read_info(data)
+        .map_err(|error| Chip8Error::Cartridge(error.to_string()))?;
+    let mut pixels = Vec::new();
+    while let Some(frame) = decoder
+        .read_next_frame()
+        .map_err(|error| Chip8Error::Cartridge(error.to_string()))?
+    {
+        pixels.extend_from_slice(&frame.buffer);
+    }
+    let cartridge = [4, 2]
+        .into_iter()
+        .find_map(|bits| payload(&pixels, bits))
+        .ok_or_else(|| Chip8Error::Cartridge("no program found in the image".to_string()))?;
+    let options = cartridge.options.rom_config();
+    Ok(RomFile {
+        data: octo::assemble(&cartridge.program)?,
+        platform: options.platform,
+        options: Some(options),
+    })
+}
+
+/// Decodes the payload stored `bits` bits per pixel, if it is valid
+fn payload(pixels: &[u8], bits: usize) -> Option<Cartridge> {
+    let per_byte = 8 / bits;
+    let mask = (1 << bits) - 1;
+    let mut bytes = pixels.chunks_exact(per_byte).map(|chunk| {
+        chunk
+            .iter()
+            .fold(0u8, |byte, pixel| byte << bits | (pixel & mask))
+    });
+    let length = bytes
+        .by_ref()
+        .take(4)
+        .fold(0usize, |length, byte| length << 8 | byte as usize);
+    let json: Vec<u8> = bytes.take(length).collect();
+    if json.len() != length {
+        return None;
+    }
+    serde_json::from_slice(&json).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::{write::FileOptions, ZipWriter};
+
+    #[test]
+    fn archive_files_are_read_in_order() {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in [
+            ("games/PONG.ch8", &b"pong"[..]),
+            ("games/", b""),
+            ("BRIX.ch8", b"brix"),
+            ("__MACOSX/BRIX.ch8", b"junk"),
+            ("games/.DS_Store", b"junk"),
+        ] {
+            writer.start_file(name, FileOptions::default()).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        let data = writer.finish().unwrap().into_inner();
+        assert_eq!(
+            archive_files(&data).unwrap(),
+            [
+                ("BRIX.ch8".to_string(), b"brix".to_vec()),
+                ("games/PONG.ch8".to_string(), b"pong".to_vec()),
+            ]
+        );
+        assert_eq!(read_entry(&data, "BRIX.ch8").unwrap(), b"brix");
+        assert!(matches!(
+            read_entry(&data, "TETRIS.ch8"),
+            Err(Chip8Error::ArchiveEntry(_))
+        ));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::{Chip8Error, CPU};
+use image::{Rgb, RgbImage};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+impl CPU {
+    /// The framebuffer in the active palette, with each pixel scaled up to `scale` × `scale`
+    pub fn frame_image(&self, scale: u32) -> RgbImage {
+        let scale = scale.max(1);
+        let [on, off] = [
+            self.settings.palette.foreground(),
+            self.settings.palette.background(),
+        ]
+        .map(|colour| {
+            let [r, g, b, _]: [u8; 4] = colour.into();
+            Rgb([r, g, b])
+        });
+        RgbImage::from_fn(
+            self.display_width as u32 * scale,
+            self.display_height as u32 * scale,
+            |x, y| {
+                let idx = (y / scale) as usize * self.display_width + (x / scale) as usize;
+                if self.framebuffer[idx] {
+                    on
+                } else {
+                    off
+                }
+            },
+        )
+    }
+
+    /// Saves the framebuffer to `path` as a PNG
+    pub fn save_screenshot(&self, path: &Path, scale: u32) -> Result<(), Chip8Error> {
+        self.frame_image(scale)
+            .save_with_format(path, image::ImageFormat::Png)
+            .map_err(Chip8Error::Screenshot)
+    }
+
+    /// First unused `<rom>-NNN` path in the screenshot directory, with `extension` if given,
+    /// creating the directory if it does not exist yet
+    pub(crate) fn capture_path(&self, extension: Option<&str>) -> io::Result<PathBuf> {
+        let directory = &self.settings.screenshots.directory;
+        fs::create_dir_all(directory)?;
+        Ok((1..)
+            .map(|number| directory.join(capture_name(&self.rom_name, number, extension)))
+            .find(|path| !path.exists())
+            .unwrap())
+    }
+
+    /// Saves the framebuffer to the first unused `<rom>-NNN.png` in the screenshot directory
+    pub fn take_screenshot(&self) -> Result<PathBuf, Chip8Error> {
+        let path = self
+            .capture_path(Some("png"))
+            .map_err(|error| Chip8Error::Screenshot(error.into()))?;
+        self.save_screenshot(&path, self.settings.screenshots.scale)?;
+        Ok(path)
+    }
+}
+
+/// File name of capture `number`, formatted directly rather than with `Path::set_extension`,
+/// which would replace part of a ROM name containing a dot
+fn capture_name(rom_name: &str, number: u32, extension: Option<&str>) -> String {
+    match extension {
+        Some(extension) => format!("{rom_name}-{number:03}.{extension}"),
+        None => format!("{rom_name}-{number:03}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_names_keep_dots_in_the_rom_name() {
+        assert_eq!(capture_name("game.v1", 1, Some("png")), "game.v1-001.png");
+        assert_eq!(capture_name("game.v1", 2, Some("gif")), "game.v1-002.gif");
+        assert_eq!(capture_name("game.v1", 12, None), "game.v1-012");
+    }
+
+    #[test]
+    fn screenshots_create_their_directory() {
+        let root = std::env::temp_dir().join(format!("chippy-shots-{}", std::process::id()));
+        let mut cpu = CPU::with_sound(None);
+        cpu.settings.screenshots.directory = root.join("nested");
+        let first = cpu.take_screenshot().unwrap();
+        let second = cpu.take_screenshot().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(first, root.join("nested/chippy-001.png"));
+        assert_eq!(second, root.join("nested/chippy-002.png"));
+    }
+}
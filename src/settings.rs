@@ -0,0 +1,581 @@
+use crate::{
+    config::Config,
+    input::{KeyMap, Keypad},
+    quirks::KeyWait,
+    recorder::RecordingFormat,
+    CPU,
+};
+use egui::{Color32, ComboBox, ScrollArea, Slider, Ui};
+use macroquad::prelude::*;
+use serde::{Deserialize, Deserializer};
+use std::{path::PathBuf, str::FromStr};
+
+/// Built-in colour palettes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Theme {
+    #[default]
+    Green,
+    Amber,
+    Lcd,
+    HighContrast,
+    ColourBlind,
+}
+
+impl Theme {
+    const ALL: [Theme; 5] = [
+        Theme::Green,
+        Theme::Amber,
+        Theme::Lcd,
+        Theme::HighContrast,
+        Theme::ColourBlind,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Theme::Green => "Classic green",
+            Theme::Amber => "Amber",
+            Theme::Lcd => "LCD",
+            Theme::HighContrast => "High contrast",
+            Theme::ColourBlind => "Colour-blind safe",
+        }
+    }
+
+    pub fn palette(self) -> Palette {
+        let colours = match self {
+            Theme::Green => [0x000000, 0x00e430, 0x00601c, 0xa0ffa8],
+            Theme::Amber => [0x000000, 0xffb000, 0x7a4a00, 0xffdc88],
+            Theme::Lcd => [0xc7f0d8, 0x43523d, 0x8fa88a, 0x1f261c],
+            Theme::HighContrast => [0x000000, 0xffffff, 0xffff00, 0x00ffff],
+            // Okabe-Ito colours, distinguishable with all common forms of colour blindness
+            Theme::ColourBlind => [0x000000, 0xe69f00, 0x56b4e9, 0xf0e442],
+        };
+        Palette {
+            colours: colours.map(rgb),
+        }
+    }
+}
+
+fn rgb(hex: u32) -> Color {
+    let [_, r, g, b] = hex.to_be_bytes();
+    Color::from_rgba(r, g, b, 255)
+}
+
+/// Colour written as `#rrggbb`
+#[derive(Clone, Copy, Debug)]
+pub struct HexColour(pub Color);
+
+impl FromStr for HexColour {
+    type Err = String;
+
+    fn from_str(colour: &str) -> Result<Self, Self::Err> {
+        let hex = colour.strip_prefix('#').unwrap_or(colour);
+        match u32::from_str_radix(hex, 16) {
+            Ok(value) if hex.len() == 6 && hex.bytes().all(|byte| byte.is_ascii_hexdigit()) => {
+                Ok(Self(rgb(value)))
+            }
+            _ => Err(format!("invalid colour: {colour} (expected #rrggbb)")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HexColour {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let colour = String::deserialize(deserializer)?;
+        colour.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Colours indexed by the bitplanes a pixel is set in: background, first plane,
+/// second plane (XO-CHIP only), and both planes (XO-CHIP only)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub colours: [Color; 4],
+}
+
+impl Palette {
+    pub fn background(&self) -> Color {
+        self.colours[0]
+    }
+
+    pub fn foreground(&self) -> Color {
+        self.colours[1]
+    }
+}
+
+/// How previous frames linger on screen to hide sprite flicker
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PersistenceMode {
+    /// Show each frame as is
+    #[default]
+    Off,
+    /// Average the last few frames
+    Blend,
+    /// Fade pixels out gradually, like phosphor on a CRT
+    Decay,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Persistence {
+    pub mode: PersistenceMode,
+    /// Number of frames averaged in blend mode
+    pub frames: usize,
+    /// Fraction of brightness a pixel keeps each frame after being turned off in decay mode
+    pub decay: f32,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self {
+            mode: PersistenceMode::Off,
+            frames: 3,
+            decay: 0.6,
+        }
+    }
+}
+
+fn hex_colour<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+    HexColour::deserialize(deserializer).map(|colour| colour.0)
+}
+
+/// How the framebuffer is fitted into the window
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScalingMode {
+    /// Fill the window, distorting pixels if the aspect ratio differs
+    #[default]
+    Stretch,
+    /// Scale as large as possible while keeping pixels square
+    Aspect,
+    /// Scale by the largest whole number that fits, keeping pixels square and uniform
+    Integer,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scaling {
+    pub mode: ScalingMode,
+    /// Colour of the letterbox around the framebuffer
+    #[serde(deserialize_with = "hex_colour")]
+    pub border: Color,
+    /// Draw lines between pixels
+    pub grid: bool,
+}
+
+impl Default for Scaling {
+    fn default() -> Self {
+        Self {
+            mode: ScalingMode::Stretch,
+            border: BLACK,
+            grid: false,
+        }
+    }
+}
+
+/// Where screenshots taken with F12 and recordings started with F10 are saved and how large they are
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Screenshots {
+    pub directory: PathBuf,
+    /// Size of each CHIP-8 pixel in image pixels
+    pub scale: u32,
+    pub recording_format: RecordingFormat,
+}
+
+impl Default for Screenshots {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("."),
+            scale: 1,
+            recording_format: RecordingFormat::Gif,
+        }
+    }
+}
+
+/// Emulation rate relative to normal, scaling both instructions and timers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Multiplier {
+    Times(f32),
+    /// As many instructions as fit in each frame
+    Unthrottled,
+}
+
+impl Multiplier {
+    pub const ALL: [Multiplier; 8] = [
+        Multiplier::Times(0.25),
+        Multiplier::Times(0.5),
+        Multiplier::Times(1.0),
+        Multiplier::Times(2.0),
+        Multiplier::Times(4.0),
+        Multiplier::Times(8.0),
+        Multiplier::Times(16.0),
+        Multiplier::Unthrottled,
+    ];
+    pub const NORMAL: Multiplier = Multiplier::Times(1.0);
+}
+
+impl Default for Multiplier {
+    fn default() -> Self {
+        Self::NORMAL
+    }
+}
+
+impl std::fmt::Display for Multiplier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Times(factor) => write!(f, "{factor}x"),
+            Self::Unthrottled => write!(f, "Unthrottled"),
+        }
+    }
+}
+
+impl FromStr for Multiplier {
+    type Err = String;
+
+    fn from_str(multiplier: &str) -> Result<Self, Self::Err> {
+        if multiplier.eq_ignore_ascii_case("unthrottled") {
+            return Ok(Self::Unthrottled);
+        }
+        let factor = multiplier.strip_suffix(['x', 'X']).unwrap_or(multiplier);
+        match factor.parse::<f32>() {
+            Ok(factor) if (0.25..=16.0).contains(&factor) => Ok(Self::Times(factor)),
+            _ => Err(format!(
+                "invalid speed multiplier: {multiplier} (expected 0.25 to 16 or unthrottled)"
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Multiplier {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Factor(f32),
+            Name(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Factor(factor) => factor.to_string().parse(),
+            Raw::Name(name) => name.parse(),
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Request made from the settings window
+pub(crate) enum MenuAction {
+    Reset,
+    Reload,
+    Launcher,
+}
+
+/// Instructions per frame unless configured, which keeps the five instructions per timer tick
+/// that chippy ran before the timers were tied to frames
+const DEFAULT_SPEED: usize = 5;
+
+/// Host-side options that can be changed while running
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Instructions executed per frame
+    pub speed: usize,
+    /// Scales the instruction rate and timers together
+    pub multiplier: Multiplier,
+    /// Multiplier while the fast-forward key is held
+    pub fast_forward: Multiplier,
+    /// Beeper volume between 0 and 1
+    pub volume: f32,
+    pub muted: bool,
+    pub palette: Palette,
+    pub persistence: Persistence,
+    pub scaling: Scaling,
+    pub fullscreen: bool,
+    pub screenshots: Screenshots,
+    pub keys: KeyMap,
+    pub keypad: Keypad,
+    /// Keypad key waiting for a host key in the remapping table
+    capture: Option<usize>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            speed: DEFAULT_SPEED,
+            multiplier: Multiplier::NORMAL,
+            fast_forward: Multiplier::Times(8.0),
+            volume: 1.0,
+            muted: false,
+            palette: Theme::default().palette(),
+            persistence: Persistence::default(),
+            scaling: Scaling::default(),
+            fullscreen: false,
+            screenshots: Screenshots::default(),
+            keys: KeyMap::default(),
+            keypad: Keypad::default(),
+            capture: None,
+        }
+    }
+}
+
+impl From<&Config> for Settings {
+    fn from(config: &Config) -> Self {
+        let mut palette = config.palette.unwrap_or_default().palette();
+        for (colour, custom) in palette.colours.iter_mut().zip(&config.colours) {
+            *colour = custom.0;
+        }
+        if let Some(background) = config.background {
+            palette.colours[0] = background.0;
+        }
+        if let Some(foreground) = config.foreground {
+            palette.colours[1] = foreground.0;
+        }
+        let mut keys = KeyMap::default();
+        keys.bind(&config.keys);
+        let mut keypad = Keypad {
+            visible: config.keypad,
+            ..Keypad::default()
+        };
+        keypad.set_labels(&config.labels);
+        let defaults = Self::default();
+        Self {
+            speed: config.speed.unwrap_or(defaults.speed).max(1),
+            multiplier: config.multiplier.unwrap_or(defaults.multiplier),
+            fast_forward: config.fast_forward.unwrap_or(defaults.fast_forward),
+            volume: config.volume.unwrap_or(1.0).clamp(0.0, 1.0),
+            muted: config.mute,
+            palette,
+            persistence: config.persistence,
+            scaling: config.scaling,
+            fullscreen: config.fullscreen,
+            screenshots: config.screenshots.clone(),
+            keys,
+            keypad,
+            capture: None,
+        }
+    }
+}
+
+impl Settings {
+    pub(crate) fn is_capturing_key(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Keeps the display and sound options of `previous`, as when switching ROMs
+    pub fn keep_display(&mut self, previous: &Settings) {
+        self.volume = previous.volume;
+        self.muted = previous.muted;
+        self.palette = previous.palette;
+        self.persistence = previous.persistence;
+        self.scaling = previous.scaling;
+        self.fullscreen = previous.fullscreen;
+        self.screenshots = previous.screenshots.clone();
+        self.keypad.visible = previous.keypad.visible;
+    }
+}
+
+impl CPU {
+    /// Pause menu and runtime settings, returning an action chosen by the player
+    pub(crate) fn settings_window(
+        &mut self,
+        egui_ctx: &egui::Context,
+        open: &mut bool,
+        paused: &mut bool,
+    ) -> Option<MenuAction> {
+        let mut action = None;
+        let settings = &mut self.settings;
+        let quirks = &mut self.quirks;
+        let rom = &self.rom_info;
+        let patches: Vec<&str> = self.patches.iter().map(|(name, _)| name.as_str()).collect();
+        if !*open {
+            settings.capture = None;
+        }
+        egui::Window::new("Settings")
+            .open(open)
+            .show(egui_ctx, |ui| {
+                ScrollArea::vertical().show(ui, |ui| {
+                    ui.heading(rom.title.as_deref().unwrap_or("Unknown ROM"));
+                    if let Some(author) = &rom.author {
+                        ui.label(format!("By {author}"));
+                    }
+                    if let Some(platform) = rom.platform {
+                        ui.label(platform.name());
+                    }
+                    ui.small(format!("SHA-1 {}", rom.hash));
+                    if !patches.is_empty() {
+                        ui.small(format!("Patched with {}", patches.join(", ")));
+                    }
+                    ui.horizontal(|ui| {
+                        if ui
+                            .button(if *paused { "Resume" } else { "Pause" })
+                            .clicked()
+                        {
+                            *paused = !*paused;
+                        }
+                        if ui.button("Reset (F2)").clicked() {
+                            action = Some(MenuAction::Reset);
+                        }
+                        if ui.button("Reload (Shift+F2)").clicked() {
+                            action = Some(MenuAction::Reload);
+                        }
+                        if ui.button("Choose ROM").clicked() {
+                            action = Some(MenuAction::Launcher);
+                        }
+                    });
+                    ui.separator();
+                    ui.heading("Emulation");
+                    ui.add(Slider::new(&mut settings.speed, 1..=50).text("Instructions per frame"));
+                    multiplier_combo(ui, &mut settings.multiplier, "Speed multiplier");
+                    multiplier_combo(ui, &mut settings.fast_forward, "Fast-forward (hold Tab)");
+                    ui.horizontal(|ui| {
+                        ui.label("Fx0A finishes on key");
+                        ui.radio_value(&mut quirks.key_wait, KeyWait::Press, "Press");
+                        ui.radio_value(&mut quirks.key_wait, KeyWait::Release, "Release");
+                    });
+                    ui.checkbox(&mut quirks.flag_last, "8xy instructions write VF last");
+                    ui.checkbox(&mut quirks.vf_reset, "8xy1-8xy3 reset VF");
+                    ui.checkbox(&mut quirks.shift_vy, "8xy6 and 8xyE shift Vy");
+                    ui.checkbox(&mut quirks.increment_index, "Fx55 and Fx65 increment I");
+                    ui.checkbox(&mut quirks.jump_vx, "Bxnn jumps to xnn + Vx");
+                    ui.checkbox(&mut quirks.clip, "Clip sprites at the screen edges");
+                    ui.separator();
+                    ui.heading("Sound");
+                    ui.horizontal(|ui| {
+                        ui.add_enabled(
+                            !settings.muted,
+                            Slider::new(&mut settings.volume, 0.0..=1.0).text("Volume"),
+                        );
+                        ui.checkbox(&mut settings.muted, "Mute");
+                    });
+                    ui.separator();
+                    ui.heading("Palette");
+                    let current = Theme::ALL
+                        .into_iter()
+                        .find(|theme| theme.palette() == settings.palette)
+                        .map_or("Custom", Theme::name);
+                    ComboBox::from_label("Theme")
+                        .selected_text(current)
+                        .show_ui(ui, |ui| {
+                            for theme in Theme::ALL {
+                                if ui
+                                    .selectable_label(current == theme.name(), theme.name())
+                                    .clicked()
+                                {
+                                    settings.palette = theme.palette();
+                                }
+                            }
+                        });
+                    let labels = ["Background", "Foreground", "Plane 2", "Both planes"];
+                    for (colour, label) in settings.palette.colours.iter_mut().zip(labels) {
+                        colour_edit(ui, colour, label);
+                    }
+                    ui.separator();
+                    ui.heading("Persistence");
+                    let persistence = &mut settings.persistence;
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut persistence.mode, PersistenceMode::Off, "Off");
+                        ui.radio_value(&mut persistence.mode, PersistenceMode::Blend, "Blend");
+                        ui.radio_value(&mut persistence.mode, PersistenceMode::Decay, "Decay");
+                    });
+                    match persistence.mode {
+                        PersistenceMode::Off => {}
+                        PersistenceMode::Blend => {
+                            ui.add(Slider::new(&mut persistence.frames, 2..=8).text("Frames"));
+                        }
+                        PersistenceMode::Decay => {
+                            ui.add(Slider::new(&mut persistence.decay, 0.1..=0.95).text("Decay"));
+                        }
+                    }
+                    ui.separator();
+                    ui.heading("Scaling");
+                    let scaling = &mut settings.scaling;
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut scaling.mode, ScalingMode::Stretch, "Stretch");
+                        ui.radio_value(&mut scaling.mode, ScalingMode::Aspect, "Aspect");
+                        ui.radio_value(&mut scaling.mode, ScalingMode::Integer, "Integer");
+                    });
+                    colour_edit(ui, &mut scaling.border, "Border");
+                    ui.checkbox(&mut scaling.grid, "Pixel grid");
+                    ui.checkbox(&mut settings.fullscreen, "Fullscreen (F11)");
+                    ui.separator();
+                    ui.heading("Capture");
+                    let screenshots = &mut settings.screenshots;
+                    ui.add(Slider::new(&mut screenshots.scale, 1..=16).text("Scale"));
+                    ui.horizontal(|ui| {
+                        ui.label("Recording");
+                        let format = &mut screenshots.recording_format;
+                        ui.radio_value(format, RecordingFormat::Gif, "GIF");
+                        ui.radio_value(format, RecordingFormat::Frames, "PNG frames + WAV");
+                    });
+                    ui.separator();
+                    ui.checkbox(&mut settings.keypad.visible, "On-screen keypad (F1)");
+                    ui.collapsing("Keys", |ui| settings.keys.ui(ui, &mut settings.capture));
+                });
+            });
+        action
+    }
+}
+
+fn multiplier_combo(ui: &mut Ui, multiplier: &mut Multiplier, label: &str) {
+    ComboBox::from_label(label)
+        .selected_text(multiplier.to_string())
+        .show_ui(ui, |ui| {
+            for choice in Multiplier::ALL {
+                ui.selectable_value(multiplier, choice, choice.to_string());
+            }
+        });
+}
+
+fn colour_edit(ui: &mut Ui, colour: &mut Color, label: &str) {
+    ui.horizontal(|ui| {
+        let [r, g, b, _]: [u8; 4] = (*colour).into();
+        let mut edited = Color32::from_rgb(r, g, b);
+        if ui.color_edit_button_srgba(&mut edited).changed() {
+            *colour = Color::from_rgba(edited.r(), edited.g(), edited.b(), 255);
+        }
+        ui.label(label);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_colours() {
+        let colour: HexColour = "#ffb000".parse().unwrap();
+        assert_eq!(colour.0, Color::from_rgba(0xFF, 0xB0, 0x00, 255));
+        let colour: HexColour = "7A4A00".parse().unwrap();
+        assert_eq!(colour.0, Color::from_rgba(0x7A, 0x4A, 0x00, 255));
+        for invalid in ["#fff", "#ffb0000", "##ffb000", "#ggb000", "#+12345", ""] {
+            assert!(invalid.parse::<HexColour>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn multipliers() {
+        assert_eq!("2".parse(), Ok(Multiplier::Times(2.0)));
+        assert_eq!("0.25x".parse(), Ok(Multiplier::Times(0.25)));
+        assert_eq!("16X".parse(), Ok(Multiplier::Times(16.0)));
+        assert_eq!("Unthrottled".parse(), Ok(Multiplier::Unthrottled));
+        for invalid in ["0.1", "17", "2xx", "x", "NaN", "fast", ""] {
+            assert!(invalid.parse::<Multiplier>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn multipliers_round_trip_through_display() {
+        for multiplier in Multiplier::ALL {
+            assert_eq!(multiplier.to_string().parse(), Ok(multiplier));
+        }
+    }
+
+    #[test]
+    fn multipliers_in_config() {
+        let config: Config =
+            toml::from_str("multiplier = 4\nfast_forward = \"unthrottled\"").unwrap();
+        assert_eq!(config.multiplier, Some(Multiplier::Times(4.0)));
+        assert_eq!(config.fast_forward, Some(Multiplier::Unthrottled));
+        assert!(toml::from_str::<Config>("multiplier = 32").is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{disassemble, Chip8Error, CPU};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    ops::RangeInclusive,
+    path::Path,
+};
+
+/// Magic bytes at the start of a binary trace
+const BINARY_MAGIC: &[u8; 4] = b"C8TR";
+const BINARY_VERSION: u8 = 1;
+const BINARY_RECORD_SIZE: usize = 31;
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum TraceFormat {
+    /// One human readable line per instruction
+    Text,
+    /// Fixed size little-endian records of 31 bytes each, after a 5 byte header:
+    /// cycle (u64), PC (u16), opcode (u16), V0-VF (16 x u8), I (u16), SP (u8)
+    Binary,
+}
+
+/// Writes a record of every executed instruction to a file
+pub struct Tracer {
+    writer: BufWriter<File>,
+    format: TraceFormat,
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl Tracer {
+    /// Only instructions whose address falls within one of `ranges` are traced, or all of them if it is empty
+    pub fn create(
+        path: impl AsRef<Path>,
+        format: TraceFormat,
+        ranges: Vec<RangeInclusive<u16>>,
+    ) -> Result<Self, Chip8Error> {
+        let file = File::create(path).map_err(Chip8Error::Trace)?;
+        let mut writer = BufWriter::new(file);
+        if let TraceFormat::Binary = format {
+            writer.write_all(BINARY_MAGIC).map_err(Chip8Error::Trace)?;
+            writer
+                .write_all(&[BINARY_VERSION])
+                .map_err(Chip8Error::Trace)?;
+        }
+        Ok(Self {
+            writer,
+            format,
+            ranges,
+        })
+    }
+
+    pub(crate) fn log(&mut self, cpu: &CPU, address: u16, opcode: u16) -> io::Result<()> {
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|range| range.contains(&address)) {
+            return Ok(());
+        }
+        match self.format {
+            TraceFormat::Text => {
+                write!(
+                    self.writer,
+                    "{:>10} 0x{address:04x} {opcode:04x} {:<16}",
+                    cpu.cycles,
+                    disassemble(opcode)
+                )?;
+                for idx in 0..16 {
+                    write!(self.writer, " V{idx:X}={:02x}", cpu.registers[idx])?;
+                }
+                writeln!(
+                    self.writer,
+                    " I={:04x} SP={}",
+                    cpu.index_register, cpu.stack_pointer
+                )
+            }
+            TraceFormat::Binary => {
+                let registers = std::array::from_fn(|idx| cpu.registers[idx]);
+                let record = binary_record(
+                    cpu.cycles,
+                    address,
+                    opcode,
+                    &registers,
+                    cpu.index_register,
+                    cpu.stack_pointer,
+                );
+                self.writer.write_all(&record)
+            }
+        }
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Encodes one instruction in the layout documented on `TraceFormat::Binary`
+fn binary_record(
+    cycles: u64,
+    address: u16,
+    opcode: u16,
+    registers: &[u8; 16],
+    index_register: u16,
+    stack_pointer: u8,
+) -> [u8; BINARY_RECORD_SIZE] {
+    let mut record = [0; BINARY_RECORD_SIZE];
+    record[0..8].copy_from_slice(&cycles.to_le_bytes());
+    record[8..10].copy_from_slice(&address.to_le_bytes());
+    record[10..12].copy_from_slice(&opcode.to_le_bytes());
+    record[12..28].copy_from_slice(registers);
+    record[28..30].copy_from_slice(&index_register.to_le_bytes());
+    record[30] = stack_pointer;
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_record_layout() {
+        let registers: [u8; 16] = std::array::from_fn(|idx| idx as u8 * 0x11);
+        let record = binary_record(0x0102_0304_0506_0708, 0x0234, 0xA2F0, &registers, 0x0FFE, 3);
+        assert_eq!(
+            record[..8],
+            [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]
+        );
+        assert_eq!(record[8..10], [0x34, 0x02]);
+        assert_eq!(record[10..12], [0xF0, 0xA2]);
+        assert_eq!(record[12..28], registers);
+        assert_eq!(record[28..30], [0xFE, 0x0F]);
+        assert_eq!(record[30], 3);
+    }
+
+    #[test]
+    fn binary_header() {
+        let path = std::env::temp_dir().join(format!("chippy-trace-{}.bin", std::process::id()));
+        let mut tracer = Tracer::create(&path, TraceFormat::Binary, Vec::new()).unwrap();
+        tracer.flush().unwrap();
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents, b"C8TR\x01");
+    }
+}